@@ -0,0 +1,75 @@
+use clap;
+use super::super::config;
+use exeupdate::contentcache::{self, CacheMode, ContentCache};
+use std::process::exit;
+use bytesize::ByteSize;
+
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap_app!(@subcommand cache =>
+        (about: "Manages the download cache shared between wineprefixes")
+        (@setting SubcommandRequiredElseHelp)
+        (@subcommand gc =>
+            (about: "Removes the least recently used files until the cache is small enough")
+            (@arg maxsize: long("max-size") +takes_value "How big the cache can be, ex 500M or 0 to empty it, defaults to cache:maxsize or 2G")
+        )
+    )
+}
+
+pub fn run(matches: &clap::ArgMatches) {
+    match matches.subcommand() {
+        ("gc", Some(matches)) => gc(matches),
+        _ => unreachable!()
+    }
+}
+
+fn gc(matches: &clap::ArgMatches) {
+    let config = config::get();
+    let max_size = match contentcache::max_size_from_config(&config, matches.value_of("maxsize")) {
+        Ok(size) => size,
+        Err(err) => {
+            println!("Invalid cache size: {}", err);
+            exit(1);
+        }
+    };
+    // The mode doesn't matter for removing files, and gc still works with the cache turned off
+    let cache = ContentCache::new(contentcache::cache_dir(), CacheMode::Copy);
+    match cache.gc(max_size) {
+        Ok(removed) => {
+            let freed: u64 = removed.iter().map(|entry| entry.size).sum();
+            if removed.len() == 0 {
+                println!("The cache is already under {}", ByteSize::b(max_size as usize));
+            } else {
+                println!("Removed {} cached file{} ({})", removed.len(),
+                         if removed.len() != 1 {"s"} else {""}, ByteSize::b(freed as usize));
+            }
+            let shared = cache.entries().map(|entries| entries.into_iter().filter(|entry| entry.shared).count()).unwrap_or(0);
+            if shared > 0 {
+                println!("{} more file{} hardlinked into installs, taking up no extra space",
+                         shared, if shared != 1 {"s are"} else {" is"});
+            }
+        },
+        Err(err) => {
+            println!("Failed to clean up the cache");
+            println!("{:?}", err);
+            exit(1);
+        }
+    }
+}
+
+/// Keeps the cache under `cache:maxsize` after an update, without making a fuss if it can't
+pub fn auto_gc(config: &::ini::Ini, cache: &ContentCache) {
+    let max_size = match contentcache::max_size_from_config(config, None) {
+        Ok(size) => size,
+        Err(err) => {
+            println!("Not cleaning up the download cache, invalid cache:maxsize: {}", err);
+            return;
+        }
+    };
+    if let Ok(removed) = cache.gc(max_size) {
+        if removed.len() > 0 {
+            let freed: u64 = removed.iter().map(|entry| entry.size).sum();
+            println!("Removed {} old file{} from the download cache ({})", removed.len(),
+                     if removed.len() != 1 {"s"} else {""}, ByteSize::b(freed as usize));
+        }
+    }
+}
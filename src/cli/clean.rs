@@ -0,0 +1,82 @@
+use clap;
+use super::super::{config, exeupdate, paths};
+use std::process::exit;
+use std::fs;
+use bytesize::ByteSize;
+
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap_app!(@subcommand clean =>
+        (about: "Removes files Warframe no longer uses")
+        (@arg orphans: --orphans "Remove files in the install that the file list doesn't mention")
+        (@arg dryrun: -n long("dry-run") "Only list what would be removed")
+        (@arg source: --source +takes_value "Where to get the file list from, defaults to update:source or origin.warframe.com")
+        (@arg offline: --offline "Use the last file list downloaded instead of getting a new one")
+    )
+}
+
+pub fn run(matches: &clap::ArgMatches) {
+    if !matches.is_present("orphans") {
+        println!("Nothing to clean, try `wfupdate clean --orphans --dry-run`");
+        exit(1);
+    }
+    let config = config::get();
+    let dryrun = matches.is_present("dryrun");
+    let mut roots = vec![];
+    match paths::game_install_dir() {
+        Some(path) => roots.push((path, false)),
+        None => {
+            println!("Can't find Warframe! Is your wineprefix setup?");
+            exit(1);
+        }
+    }
+    if let Some(mut path) = paths::launcher_dir() {
+        path.push("Downloaded");
+        path.push("Public");
+        if path.is_dir() {
+            roots.push((path, true));
+        }
+    }
+
+    let index = super::index::current_index_text(&config, matches.value_of("source"), matches.is_present("offline"));
+    // No update:lenient here, anything it skipped would look like an orphan
+    let files = match exeupdate::parser::parse_file_list(index) {
+        Ok(files) => files,
+        Err(err) => {
+            println!("Failed to parse the Warframe file list, {}", err);
+            println!("Not cleaning anything, since files it doesn't list properly would look like orphans");
+            exit(1);
+        }
+    };
+    if files.len() == 0 {
+        // Everything would look like an orphan
+        println!("The file list is empty, not cleaning anything");
+        exit(1);
+    }
+    let pins = exeupdate::pins::Pins::from_config(&config);
+    let orphans = match exeupdate::orphans::find_orphans(&files, &roots, &pins) {
+        Ok(orphans) => orphans,
+        Err(err) => {
+            println!("Failed to look through the install");
+            println!("{:?}", err);
+            exit(1);
+        }
+    };
+
+    let mut removed = 0usize;
+    let mut removed_bytes = 0u64;
+    for path in orphans {
+        let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        if dryrun {
+            println!("Would remove {} ({})", path.display(), ByteSize::b(size as usize));
+        } else if let Err(err) = fs::remove_file(&path) {
+            println!("Failed to remove {}: {}", path.display(), err);
+            continue;
+        } else {
+            println!("Removed {} ({})", path.display(), ByteSize::b(size as usize));
+        }
+        removed += 1;
+        removed_bytes += size;
+    }
+    println!("{} {} orphaned file{} ({})", if dryrun {"Found"} else {"Removed"}, removed,
+             if removed != 1 {"s"} else {""}, ByteSize::b(removed_bytes as usize));
+}
@@ -0,0 +1,208 @@
+use clap;
+use super::super::{config, exeupdate};
+use exeupdate::diff::IndexDiff;
+use exeupdate::stats::Tally;
+use std::process::exit;
+use std::fs::File;
+use std::io::prelude::*;
+use bytesize::ByteSize;
+
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap_app!(@subcommand index =>
+        (about: "Inspects the stage 1 file list")
+        (@setting SubcommandRequiredElseHelp)
+        (@subcommand diff =>
+            (about: "Shows what changed between two file lists, defaults to the last two downloaded")
+            (@arg old: "The older file list (index.txt or index.txt.lzma)")
+            (@arg new: "The newer file list (index.txt or index.txt.lzma)")
+        )
+        (@subcommand stats =>
+            (about: "Shows file counts and sizes, and estimates how much space a fresh install needs")
+            (@arg index: "The file list to look at (index.txt or index.txt.lzma), defaults to the current one")
+        )
+    )
+}
+
+pub fn run(matches: &clap::ArgMatches) {
+    match matches.subcommand() {
+        ("diff", Some(matches)) => diff(matches),
+        ("stats", Some(matches)) => stats(matches),
+        _ => unreachable!()
+    }
+}
+
+/// Reads an index from disk, decompressing it first if it's a `.lzma`
+fn read_index_file(path: &str) -> Vec<exeupdate::File> {
+    let contents = File::open(path).map_err(From::from).and_then(|mut f| {
+        if path.ends_with(".lzma") {
+            exeupdate::downloader::decompress(f)
+        } else {
+            let mut contents = vec![];
+            try!(f.read_to_end(&mut contents));
+            Ok(contents)
+        }
+    });
+    let contents = match contents {
+        Ok(contents) => String::from_utf8_lossy(contents.as_slice()).into_owned(),
+        Err(err) => {
+            println!("Couldn't read {}", path);
+            println!("{:?}", err);
+            exit(1);
+        }
+    };
+    parse_index(contents, path)
+}
+
+/// Parses an index, exiting with what's wrong with it if it can't be.
+///
+/// If `update:lenient` is set, bad lines are skipped with a warning instead.
+pub fn parse_index(index: String, name: &str) -> Vec<exeupdate::File> {
+    if config::parse_bool(config::get().get_from(Some("update"), "lenient")) {
+        let (list, errors) = exeupdate::parser::parse_file_list_lenient(index);
+        for err in errors {
+            println!("Skipping bad entry in {}, {}", name, err);
+        }
+        return list;
+    }
+    match exeupdate::parser::parse_file_list(index) {
+        Ok(list) => list,
+        Err(err) => {
+            println!("Failed to parse {}, {}", name, err);
+            println!("Is it a Warframe file list? Set update:lenient to true to skip lines like this.");
+            exit(1);
+        }
+    }
+}
+
+/// Gets and parses the current index, from the cache if it's fresh enough (or whatever its age, if `offline`).
+///
+/// `source` overrides `update:source`, like `--source` does.
+pub fn current_index(config: &::ini::Ini, source: Option<&str>, offline: bool) -> Vec<exeupdate::File> {
+    parse_index(current_index_text(config, source, offline), "the Warframe file list")
+}
+
+/// Gets the current index like `current_index()`, without parsing it
+pub fn current_index_text(config: &::ini::Ini, source: Option<&str>, offline: bool) -> String {
+    let source = match exeupdate::source::from_config(config, source, 1, false) {
+        Ok(source) => source,
+        Err(err) => {
+            println!("Invalid content source: {}", err);
+            exit(1);
+        }
+    };
+    let ttl = exeupdate::indexcache::ttl_from_config(config);
+    match exeupdate::indexcache::get_index(&*source, ttl, offline) {
+        Ok((index, _)) => index,
+        Err(_) => {
+            if offline {
+                println!("No saved Warframe file list, run `wfupdate update check` online first.");
+            } else {
+                println!("Failed to get Warframe file list. Are you connected to the internet?");
+            }
+            exit(1);
+        }
+    }
+}
+
+fn diff(matches: &clap::ArgMatches) {
+    let old = match matches.value_of("old") {
+        Some(path) => read_index_file(path),
+        None => match exeupdate::indexcache::load_previous() {
+            Some(cached) => parse_index(cached.index, "the previous file list"),
+            None => {
+                println!("Only one file list has been downloaded so far, there's nothing to compare it to.");
+                exit(1);
+            }
+        }
+    };
+    let new = match matches.value_of("new") {
+        Some(path) => read_index_file(path),
+        None => match exeupdate::indexcache::load() {
+            Some(cached) => parse_index(cached.index, "the cached file list"),
+            None => {
+                println!("No file list has been downloaded yet, run `wfupdate update check` first.");
+                exit(1);
+            }
+        }
+    };
+    print_diff(&exeupdate::diff::diff(&old, &new), &super::update::category_rules(&config::get()));
+}
+
+/// Formats a signed number of bytes, ex `+1.2 MB` or `-300 B`
+fn signed_size(bytes: i64) -> String {
+    format!("{}{}", if bytes < 0 {"-"} else {"+"}, ByteSize::b(bytes.abs() as usize))
+}
+
+/// Prints an index diff, grouped by file type
+pub fn print_diff(diff: &IndexDiff, rules: &exeupdate::parser::Rules) {
+    if diff.is_empty() {
+        println!("No changes");
+        return;
+    }
+    for (filetype, part) in diff.by_type(rules) {
+        println!("{}:", filetype.name());
+        for file in &part.added {
+            println!("  + {} ({})", file.disk_path, ByteSize::b(file.size as usize));
+        }
+        for file in &part.removed {
+            println!("  - {} ({})", file.disk_path, ByteSize::b(file.size as usize));
+        }
+        for &(ref old, ref new) in &part.changed {
+            println!("  ~ {} ({} -> {})", new.disk_path, ByteSize::b(old.size as usize), ByteSize::b(new.size as usize));
+        }
+    }
+    println!("{} added, {} removed, {} changed, {} compressed", diff.added.len(), diff.removed.len(),
+             diff.changed.len(), signed_size(diff.size_delta()));
+}
+
+fn print_tally(name: &str, tally: &Tally) {
+    println!("  {:<16} {:>6} file{} {:>10}", name, tally.files, if tally.files != 1 {"s"} else {" "},
+             format!("{}", ByteSize::b(tally.bytes as usize)));
+}
+
+fn stats(matches: &clap::ArgMatches) {
+    let config = config::get();
+    let files = match matches.value_of("index") {
+        Some(path) => read_index_file(path),
+        None => current_index(&config, None, false)
+    };
+
+    let rules = super::update::category_rules(&config);
+    let stats = exeupdate::stats::stats(&files, &rules);
+    println!("By type:");
+    for &(filetype, ref tally) in &stats.by_type {
+        print_tally(filetype.name(), tally);
+    }
+    println!("By directory:");
+    for &(ref dir, ref tally) in &stats.by_dir {
+        print_tally(dir.as_str(), tally);
+    }
+    println!("By architecture:");
+    for &(arch, ref tally) in &stats.by_arch {
+        print_tally(arch.to_string().as_str(), tally);
+    }
+    println!("Total:");
+    print_tally("everything", &stats.total);
+
+    // A fresh install gets the stage 1 files the config asks for, then the game fetches its assets itself in stage 2.
+    // Only the game assets stage 1 didn't already get are counted for stage 2, anything else the game
+    // might fetch isn't in the index, so the stage 2 figure is labelled as game assets only.
+    let mut stage1 = Tally::default();
+    let mut stage2 = Tally::default();
+    for file in &files {
+        let filetype = rules.categorize(file);
+        if super::update::stage1_wants(filetype, &config) {
+            stage1.add(file);
+        } else if filetype == exeupdate::FileType::GameAsset {
+            stage2.add(file);
+        }
+    }
+    println!("Fresh install (game:64bit = {}, update:steam = {}):",
+             config::parse_bool(config.get_from(Some("game"), "64bit")),
+             config::parse_bool(config.get_from(Some("update"), "steam")));
+    print_tally("stage 1", &stage1);
+    print_tally("stage 2 (assets)", &stage2);
+    println!("Stage 2 only counts game assets listed in the index, the game may fetch more than that.");
+    println!("At least {} of disk space needed. These are compressed sizes, the installed game will be somewhat bigger.",
+             ByteSize::b((stage1.bytes + stage2.bytes) as usize));
+}
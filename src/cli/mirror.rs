@@ -0,0 +1,178 @@
+use clap;
+use super::super::{config, exeupdate};
+use exeupdate::FileType;
+use exeupdate::mirror::MirrorHandler;
+use std::process::exit;
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::fs::File;
+use hyper::server::Server;
+use bytesize::ByteSize;
+
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap_app!(@subcommand mirror =>
+        (about: "Builds and serves a local copy of the Warframe content server")
+        (@setting SubcommandRequiredElseHelp)
+        (@subcommand sync =>
+            (about: "Downloads the index and the stage 1 files it lists into a directory")
+            (@arg dir: +required "Directory to keep the mirror in")
+            (@arg types: -t --types +takes_value "Comma-separated file types to mirror (exe32, exe64, directx, drivers, redist, language, launcher, steam, game, unknown), defaults to everything but game")
+            (@arg source: --source +takes_value "Where to mirror from, defaults to update:source or origin.warframe.com")
+            (@arg verbose: -v --verbose "Output added debugging information")
+        )
+        (@subcommand serve =>
+            (about: "Serves a mirror directory over HTTP, for use as another machine's update:source")
+            (@arg dir: +required "The mirror directory")
+            (@arg listen: -l --listen +takes_value "Address to listen on, defaults to 0.0.0.0:8080")
+            (@arg verbose: -v --verbose "Print every request")
+        )
+    )
+}
+
+pub fn run(matches: &clap::ArgMatches) {
+    match matches.subcommand() {
+        ("sync", Some(matches)) => sync(matches),
+        ("serve", Some(matches)) => serve(matches),
+        _ => unreachable!()
+    }
+}
+
+fn sync(matches: &clap::ArgMatches) {
+    let config = config::get();
+    let verbose = matches.is_present("verbose");
+    let dir = PathBuf::from(matches.value_of("dir").unwrap());
+    let types: Vec<FileType> = match matches.value_of("types") {
+        Some(list) => list.split(',').map(|name| match FileType::from_name(name.trim()) {
+            Some(t) => t,
+            None => {
+                println!("Unknown file type \"{}\"", name);
+                exit(1);
+            }
+        }).collect(),
+        None => FileType::all().into_iter().filter(|t| *t != FileType::GameAsset).collect()
+    };
+    let rules = super::update::category_rules(&config);
+    let source = match exeupdate::source::from_config(&config, matches.value_of("source"), 1, verbose) {
+        Ok(source) => source,
+        Err(err) => {
+            println!("Invalid content source: {}", err);
+            exit(1);
+        }
+    };
+    if let Err(err) = fs::create_dir_all(&dir) {
+        println!("Can't create {:?}: {}", dir, err);
+        exit(1);
+    }
+
+    // The index is only moved into place once every file it lists is there,
+    // so nobody updating from the mirror mid-sync gets pointed at missing files.
+    println!("Downloading file list from {}...", source.describe());
+    let index_part = dir.join(".index.txt.lzma.part");
+    let _ = fs::remove_file(&index_part);
+    let index = exeupdate::downloader::download_to(&*source, source.index_path().as_str(), &index_part)
+        .and_then(|_| File::open(&index_part).map_err(From::from))
+        .and_then(exeupdate::downloader::decompress);
+    let index = match index {
+        Ok(index) => String::from_utf8(index).unwrap(),
+        Err(err) => {
+            println!("Failed to get Warframe file list. Are you connected to the internet?");
+            println!("{:?}", err);
+            exit(1);
+        }
+    };
+    let parsed = super::update::safe_files(super::index::parse_index(index, "the Warframe file list"));
+
+    let mut downloaded = 0usize;
+    let mut downloaded_bytes = 0u64;
+    let mut up_to_date = 0usize;
+    for file in parsed {
+        if !types.contains(&rules.categorize(&file)) {
+            continue;
+        }
+        let dest = dir.join(file.download_path.trim_left_matches('/'));
+        let have = match fs::metadata(&dest) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0
+        };
+        if have == file.size && intact(&dest, &file) {
+            if verbose {
+                println!("Up to date: {}", file.download_path);
+            }
+            up_to_date += 1;
+            continue;
+        } else if have >= file.size {
+            // Corrupt, start it over
+            let _ = fs::remove_file(&dest);
+        }
+        println!("Downloading {} ({})", file.download_path, ByteSize::b(file.size as usize));
+        let mut ok = false;
+        for _ in 0..exeupdate::downloader::DOWNLOAD_ATTEMPTS {
+            let result = fs::create_dir_all(dest.parent().unwrap()).map_err(From::from)
+                .and_then(|_| exeupdate::downloader::download_to(&*source, file.download_path.as_str(), &dest));
+            if let Err(err) = result {
+                println!("Failed to download {}", file.download_path);
+                println!("{:?}", err);
+                exit(1);
+            }
+            if intact(&dest, &file) {
+                ok = true;
+                break;
+            }
+            let _ = fs::remove_file(&dest);
+        }
+        if !ok {
+            println!("Downloaded {} didn't match its MD5 hash after {} attempts, try syncing again",
+                     file.download_path, exeupdate::downloader::DOWNLOAD_ATTEMPTS);
+            exit(1);
+        }
+        downloaded += 1;
+        downloaded_bytes += file.size;
+    }
+    if let Err(err) = fs::rename(&index_part, dir.join("index.txt.lzma")) {
+        println!("Failed to save the file list");
+        println!("{:?}", err);
+        exit(1);
+    }
+    println!("{} file{} downloaded ({}), {} already up to date", downloaded, if downloaded != 1 {"s"} else {""},
+             ByteSize::b(downloaded_bytes as usize), up_to_date);
+}
+
+/// Whether the mirrored copy of `file` at `path` decompresses to what the index says it should
+fn intact(path: &Path, file: &exeupdate::File) -> bool {
+    match File::open(path).map_err(From::from).and_then(exeupdate::downloader::hash_compressed) {
+        Ok(hash) => hash == file.md5sum,
+        Err(_) => false
+    }
+}
+
+fn serve(matches: &clap::ArgMatches) {
+    let dir = PathBuf::from(matches.value_of("dir").unwrap());
+    if !dir.join("index.txt.lzma").exists() {
+        println!("{:?} doesn't look like a mirror, run `wfupdate mirror sync` first", dir);
+        exit(1);
+    }
+    let listen = matches.value_of("listen").unwrap_or("0.0.0.0:8080");
+    let server = match Server::http(listen) {
+        Ok(server) => server,
+        Err(err) => {
+            println!("Can't listen on {}: {}", listen, err);
+            exit(1);
+        }
+    };
+    match server.handle(MirrorHandler {
+        root: dir.clone(),
+        verbose: matches.is_present("verbose")
+    }) {
+        Ok(listening) => {
+            println!("Serving {:?} on http://{}/", dir, listening.socket);
+            println!("Point other machines at it with `wfupdate config set update:source \"http://<this machine>:{}/;{}\"`",
+                     listening.socket.port(), exeupdate::source::WARFRAME_CONTENT_URL);
+            println!("Files that weren't mirrored (game assets, unless synced with --types) will come from origin");
+            // Dropping `listening` waits for the server threads, which run until we're killed.
+        },
+        Err(err) => {
+            println!("Failed to start the server: {}", err);
+            exit(1);
+        }
+    }
+}
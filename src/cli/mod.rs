@@ -3,3 +3,7 @@ pub mod run;
 pub mod update;
 pub mod wine;
 pub mod repair;
+pub mod mirror;
+pub mod index;
+pub mod clean;
+pub mod cache;
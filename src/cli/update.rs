@@ -2,11 +2,14 @@ use clap;
 use super::super::{config, exeupdate, run, paths, logparser};
 use std::process::{Stdio, exit};
 use std::path::PathBuf;
+use std::fs;
 use logparser::LogLine;
+use exeupdate::source::ContentSource;
+use std::sync::{Arc, Mutex};
+use std::collections::HashSet;
+use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::fs::create_dir_all;
-use bytesize;
 use bytesize::ByteSize;
 use encoding::{Encoding, DecoderTrap};
 use encoding::all::WINDOWS_1251;
@@ -19,6 +22,12 @@ pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
         (@arg stage2: short("2") --stage2 "Run a stage 2 update (game assets), defaults to both")
         (@arg verbose: -v --verbose "Output added debugging information")
         (@arg rawlines: -r --rawlines "Output raw log lines from Warframe")
+        (@arg restart: --restart "Throw away an interrupted stage 1 update instead of resuming it")
+        (@arg source: --source +takes_value "Where to get stage 1 files from: a URL, a local mirror directory, or a .tar of one")
+        (@arg rehash: --rehash "Hash every file again instead of trusting hashes from earlier checks")
+        (@arg limitrate: long("limit-rate") +takes_value "Cap stage 1 download speed across all downloads, ex 500K or 2M (bytes per second)")
+        (@arg nospacecheck: long("no-space-check") "Update even if there doesn't seem to be enough free disk space")
+        (@arg dryrun: -n long("dry-run") "List the stage 1 files an update would download and where they'd go, without changing anything")
         (@subcommand check =>
             (about: "Check for updates, but don't actually update")
             (@arg stage2: short("2") --stage2 "Check for a stage 2 update, only checks stage 1 by default")
@@ -26,6 +35,13 @@ pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
             (@arg fullcheck: -f --full "Check all the files, including the 35GB+ of game assets")
             (@arg silent: -s --silent "Don't output anything, just exit with a status code")
             (@arg verbose: -v --verbose "Output added debugging information")
+            (@arg source: --source +takes_value "Where to get stage 1 files from: a URL, a local mirror directory, or a .tar of one")
+            (@arg offline: --offline "Check against the last file list downloaded instead of getting a new one")
+            (@arg rehash: --rehash "Hash every file again instead of trusting hashes from earlier checks")
+        )
+        (@subcommand rollback =>
+            (about: "Restores the files replaced by the last stage 1 update")
+            (@arg list: -l --list "List saved snapshots instead of restoring one")
         )
     )
 }
@@ -51,6 +67,15 @@ pub fn run(matches: &clap::ArgMatches) {
                 println!("No game assets need updating");
             }
         }
+    } else if let Some(matches) = matches.subcommand_matches("rollback") {
+        rollback(matches);
+    } else if matches.is_present("dryrun") {
+        if matches.is_present("stage1") || !matches.is_present("stage2") {
+            stage1_dry_run(matches, &config);
+        }
+        if matches.is_present("stage2") || !matches.is_present("stage1") {
+            println!("Stage 2 isn't included in a dry run, see `wfupdate update check --stage2`");
+        }
     } else {
         // To explain:
         // flags | stage1 | stage2
@@ -68,7 +93,7 @@ pub fn run(matches: &clap::ArgMatches) {
             if both {
                 println!("Running Stage 1 update...");
             }
-            stage1_update(&config);
+            stage1_update(matches, &config);
         }
         if stage2 {
             if both {
@@ -80,60 +105,148 @@ pub fn run(matches: &clap::ArgMatches) {
 }
 
 
+/// Sets up the content source from `--source` or the config
+fn content_source(matches: &clap::ArgMatches, config: &::ini::Ini, jobs: usize) -> Arc<ContentSource> {
+    match exeupdate::source::from_config(config, matches.value_of("source"), jobs, matches.is_present("verbose")) {
+        Ok(source) => source,
+        Err(err) => {
+            println!("Invalid content source: {}", err);
+            exit(1);
+        }
+    }
+}
+
+/// Wraps `source` in the speed limit from `--limit-rate` or `network:maxrate`, if there is one
+fn throttled(matches: &clap::ArgMatches, config: &::ini::Ini, source: Arc<ContentSource>) -> Arc<ContentSource> {
+    match exeupdate::ratelimit::from_config(config, matches.value_of("limitrate")) {
+        Ok(Some(rate)) => {
+            println!("Limiting downloads to {}/s", ByteSize::b(rate as usize));
+            Arc::new(exeupdate::ratelimit::ThrottledSource::new(source, rate))
+        },
+        Ok(None) => source,
+        Err(err) => {
+            println!("Invalid download speed limit: {}", err);
+            exit(1);
+        }
+    }
+}
+
+/// Formats a number of seconds as a rough, human-readable duration
+fn describe_age(seconds: i64) -> String {
+    let (amount, unit) = if seconds < 120 {
+        (seconds, "second")
+    } else if seconds < 2 * 60 * 60 {
+        (seconds / 60, "minute")
+    } else if seconds < 2 * 24 * 60 * 60 {
+        (seconds / (60 * 60), "hour")
+    } else {
+        (seconds / (24 * 60 * 60), "day")
+    };
+    format!("{} {}{}", amount, unit, if amount != 1 {"s"} else {""})
+}
+
 fn stage1_check(matches: &clap::ArgMatches, config: &::ini::Ini) {
     use exeupdate::FileType::*;
     let verbose = matches.is_present("verbose");
     let silent = matches.is_present("silent");
+    let offline = matches.is_present("offline");
+    let source = content_source(matches, config, 1);
     if !silent { println!("Getting file list..."); }
-    let index = match exeupdate::downloader::get_index() {
-        Ok(index) => index,
+    let ttl = exeupdate::indexcache::ttl_from_config(config);
+    let index = match exeupdate::indexcache::get_index(&*source, ttl, offline) {
+        Ok((index, cached)) => {
+            if let Some(cached) = cached {
+                if !silent { println!("Using file list from {} ago", describe_age(cached.age())); }
+            }
+            index
+        },
         Err(_) => {
-            println!("Failed to get Warframe file list. Are you connected to the internet?");
+            if offline {
+                println!("No saved Warframe file list to check against, run `wfupdate update check` online first.");
+            } else {
+                println!("Failed to get Warframe file list. Are you connected to the internet?");
+                println!("(--offline will check against the last file list downloaded)");
+            }
             exit(1);
         }
     };
     if !silent { println!("Parsing file list..."); }
-    let parsed = match exeupdate::parser::parse_file_list(index) {
-        Ok(list) => list,
-        Err(_) => {
-            println!("Failed to parse Warframe file list. Did DE change something?");
-            exit(1);
-        }
-    };
+    let parsed = safe_files(super::index::parse_index(index, "the Warframe file list"));
     if !silent { println!("Checking Files..."); }
+    let rules = category_rules(config);
+    let mut to_check = vec![];
     for item in parsed {
-        let check = match exeupdate::parser::categorize(&item) {
-            Exe32Bit | LauncherAsset => true,
-            SteamAsset => config::parse_bool(config.get_from(Some("update"), "steam")),
-            GameAsset => matches.is_present("full"),
-            Exe64Bit => matches.is_present("64bit") || config::parse_bool(config.get_from(Some("game"), "64bit")),
-            Unknown => true
-        } || matches.is_present("full");
-        let display_path = item.disk_path.clone();
-        if !check {
-            if verbose && exeupdate::parser::categorize(&item) != GameAsset {
-                println!("Skipping {}", display_path);
-            }
-        } else {
-            match exeupdate::checker::check_file(&item) {
-                Ok(needs_update) => {
-                    if needs_update {
-                        if verbose {
-                            println!("Needs update: {}", display_path);
-                        } else if !silent {
-                            println!("{}", display_path);
-                        }
-                    } else {
-                        if verbose {
-                            println!("Up to date: {}", display_path);
-                        }
-                    }
-                },
-                Err(err) => {
-                    println!("Error {}", display_path);
-                    println!("{}", err);
-                }
+        let filetype = rules.categorize(&item);
+        let check = matches.is_present("fullcheck") ||
+                    (filetype == Exe64Bit && matches.is_present("is64bit")) ||
+                    stage1_wants(filetype, config);
+        if check {
+            to_check.push(item);
+        } else if verbose && filetype != GameAsset {
+            println!("Skipping {}", item.disk_path);
+        }
+    }
+    let (to_check, pinned) = exeupdate::pins::Pins::from_config(config).partition(to_check);
+    let needs_update: HashSet<String> = check_files(to_check.clone(), matches.is_present("rehash"), silent, true).into_iter()
+        .map(|file| file.disk_path).collect();
+    for item in to_check {
+        if needs_update.contains(&item.disk_path) {
+            if verbose {
+                println!("Needs update: {}", item.disk_path);
+            } else if !silent {
+                println!("{}", item.disk_path);
             }
+        } else if verbose {
+            println!("Up to date: {}", item.disk_path);
+        }
+    }
+    if !silent && pinned.len() > 0 {
+        println!("Pinned, not checked:");
+        for item in pinned {
+            println!("  {}", item.disk_path);
+        }
+    }
+}
+
+/// Hashes `files` on every CPU core, and returns the ones that need updating.
+///
+/// Files that haven't changed since they were last hashed are skipped, unless `rehash` is set.
+/// Unless `quiet` is set, a running total of how much has been hashed is shown while it works.
+/// With `save`, the hashes are kept for next time.
+fn check_files(files: Vec<exeupdate::File>, rehash: bool, quiet: bool, save: bool) -> Vec<exeupdate::File> {
+    if rehash {
+        exeupdate::checker::forget_hashes(&files);
+    }
+    // Only redraw every 64MB, printing on every chunk would slow the hashing down.
+    const REPORT_EVERY: u64 = 64 * 1024 * 1024;
+    let next_report = Arc::new(Mutex::new(REPORT_EVERY));
+    let reported = next_report.clone();
+    let result = exeupdate::checker::check_files(files, 0, move |hashed| {
+        if quiet {
+            return;
+        }
+        let mut next_report = reported.lock().unwrap();
+        if hashed >= *next_report {
+            *next_report = hashed + REPORT_EVERY;
+            print!("\r{} hashed", ByteSize::b(hashed as usize));
+            let _ = io::stdout().flush();
+        }
+    });
+    if *next_report.lock().unwrap() > REPORT_EVERY {
+        println!("");
+    }
+    if save {
+        if let Err(err) = exeupdate::checker::save_hash_cache() {
+            println!("Failed to save file hashes, the next check will hash everything again");
+            println!("{:?}", err);
+        }
+    }
+    match result {
+        Ok(needs_update) => needs_update,
+        Err(err) => {
+            println!("Failed to check files");
+            println!("{}", err);
+            exit(1);
         }
     }
 }
@@ -178,73 +291,398 @@ fn stage2_check(matches: &clap::ArgMatches, wfpath: PathBuf) -> bool {
 }
 
 
-fn stage1_update(config: &::ini::Ini) {
-    use exeupdate::FileType::*;
-    println!("Downloading file list...");
-    let index = match exeupdate::downloader::get_index() {
-        Ok(index) => index,
-        Err(_) => {
-            println!("Failed to get Warframe file list. Are you connected to the internet?");
+fn stage1_update(matches: &clap::ArgMatches, config: &::ini::Ini) {
+    if matches.is_present("restart") {
+        let _ = exeupdate::plan::clear();
+        let _ = exeupdate::downloader::clear_staging();
+    }
+    let jobs = config::parse_usize(config.get_from(Some("update"), "jobs"), 4);
+    let source = content_source(matches, config, jobs);
+    let (index, new_index) = stage1_index(&*source, config, false);
+    let to_update = stage1_files(matches, index, config, false);
+    println!("{} file{} to update", to_update.len(), if to_update.len() != 1 {"s"} else {""});
+    if !matches.is_present("nospacecheck") {
+        if let Err(err) = enough_space(stage1_space(&to_update)) {
+            println!("{}", err);
             exit(1);
         }
-    };
-    let parsed = match exeupdate::parser::parse_file_list(index) {
-        Ok(list) => list,
-        Err(_) => {
-            println!("Failed to parse Warframe file list. Did DE change something?");
+    }
+    // Everything gets downloaded and staged first, and then applied in one go,
+    // so a failure partway through never leaves a mix of old and new files.
+    let mut batch = exeupdate::update::UpdateBatch::new();
+    let total_files = to_update.len();
+    let total_bytes: u64 = to_update.iter().map(|file| file.size).sum();
+    let mut done_files = 0usize;
+    let mut done_bytes = 0u64;
+    let destinations = to_update.iter()
+        .map(|file| (file.clone(), paths::realize_path(file.disk_path.clone()).unwrap()))
+        .collect();
+    let source = throttled(matches, config, source);
+    let cache = match exeupdate::contentcache::ContentCache::from_config(config) {
+        Ok(cache) => cache,
+        Err(err) => {
+            println!("Invalid download cache setting: {}", err);
             exit(1);
         }
     };
-    let mut to_update: Vec<exeupdate::File> = vec![];
-    println!("Checking files...");
-    for item in parsed {
-        let check = match exeupdate::parser::categorize(&item) {
-            Exe32Bit | LauncherAsset => true,
-            SteamAsset => config::parse_bool(config.get_from(Some("update"), "steam")),
-            GameAsset => false,
-            Exe64Bit => config::parse_bool(config.get_from(Some("game"), "64bit")),
-            Unknown => true
-        };
-        let display_path = item.disk_path.clone();
-        if check {
-            match exeupdate::checker::check_file(&item) {
-                Ok(needs_update) => {
-                    if needs_update {
-                        to_update.push(item);
+    let downloads = exeupdate::downloader::get_verified_files(source, destinations, jobs, cache.clone());
+    while let Ok((file, result)) = downloads.recv() {
+        let display = file.disk_path.clone();
+        let temp = match result {
+            Ok(temp) => temp,
+            Err(err) => {
+                match err {
+                    exeupdate::downloader::DownloaderError::HashMismatch(..) => {
+                        println!("Failed to download {}: file didn't match its MD5 hash after {} attempts",
+                                 display, exeupdate::downloader::DOWNLOAD_ATTEMPTS);
+                    },
+                    ref err if err.is_not_found() => {
+                        // The file list this update was planned from is out of date, so the plan is no good either
+                        println!("Failed to download {}: it isn't on the server any more", display);
+                        println!("The file list has probably changed, run the update again to start over");
+                        let _ = exeupdate::plan::clear();
+                        let _ = exeupdate::indexcache::expire();
+                    },
+                    err => {
+                        println!("Failed to download {}", display);
+                        println!("{:?}", err);
                     }
-                },
-                Err(err) => {
-                    println!("Failed to check {}", display_path);
-                    println!("{}", err);
                 }
+                batch.abort();
+                exeupdate::downloader::discard_downloads(downloads);
+                exit(1);
             }
-        }
+        };
+        done_files += 1;
+        done_bytes += file.size;
+        println!("[{}/{} files, {}/{}] Downloaded {}", done_files, total_files,
+                 ByteSize::b(done_bytes as usize), ByteSize::b(total_bytes as usize), display);
+        batch.stage_temp(paths::realize_path(file.disk_path.clone()).unwrap(), temp);
     }
-    println!("{} file{} to update", to_update.len(), if to_update.len() != 1 {"s"} else {""});
-    for file in to_update {
-        let display = file.disk_path.clone();
-        println!("Downloading {} ({})", display, bytesize::ByteSize::b(file.size as usize));
-        let newcontent = match exeupdate::downloader::get_file(file.download_path) {
-            Ok(content) => content,
+    if batch.len() == 0 {
+        return;
+    }
+    let keep = config::parse_usize(config.get_from(Some("update"), "snapshots"), 3);
+    let mut snapshot = None;
+    if keep > 0 {
+        match exeupdate::snapshot::create(&to_update) {
+            Ok(Some(saved)) => {
+                println!("Saved {} old file{} for rollback", saved.entries.len(),
+                         if saved.entries.len() != 1 {"s"} else {""});
+                snapshot = Some(saved);
+            },
+            Ok(None) => {},
             Err(err) => {
-                println!("Failed to download {}", display);
+                println!("Failed to save a rollback snapshot, not updating");
                 println!("{:?}", err);
+                batch.abort();
                 exit(1);
             }
+        }
+    }
+    println!("Applying updated files");
+    match batch.commit() {
+        Err(exeupdate::update::CommitError::RolledBack(err)) => {
+            println!("Failed to apply update, no files were changed");
+            println!("{:?}", err);
+            if let Some(snapshot) = snapshot {
+                let _ = exeupdate::snapshot::delete(snapshot);
+            }
+            exit(1);
+        },
+        result => {
+            if let Err(exeupdate::update::CommitError::NotSynced(err)) = result {
+                println!("Files updated, but they might not all be safely on disk yet");
+                println!("{:?}", err);
+            } else {
+                println!("Files successfully updated");
+            }
+            // Only now that the new snapshot is worth keeping can it push older ones out
+            if snapshot.is_some() {
+                if let Err(err) = exeupdate::snapshot::prune(keep, &paths::plat::wineprefix()) {
+                    println!("Failed to remove old snapshots");
+                    println!("{:?}", err);
+                }
+            }
+            let _ = exeupdate::plan::clear();
+            let _ = exeupdate::downloader::clear_staging();
+            if let Some(cache) = cache {
+                super::cache::auto_gc(config, &cache);
+            }
+            if new_index {
+                print_index_changes(config);
+            }
+        }
+    };
+}
+
+/// How much room to leave on top of what an update says it needs.
+///
+/// The sizes in the file list and from `BytesToDownload` are compressed, so the files will take up more than that once they're written out.
+const FREE_SPACE_MARGIN: u64 = 256 * 1024 * 1024;
+
+/// Makes sure there's room for an update: `needs` pairs each directory it writes to with how many bytes go there.
+///
+/// Directories on the same filesystem are added up, ex when the staging area is on the same disk as the wineprefix,
+/// and each filesystem needs `FREE_SPACE_MARGIN` on top of that.
+/// Returns a message for the user if there isn't enough room. Filesystems whose free space can't be worked out are let through.
+fn enough_space(needs: Vec<(PathBuf, u64)>) -> Result<(), String> {
+    // (filesystem, a directory on it, bytes needed)
+    let mut filesystems: Vec<(Option<u64>, PathBuf, u64)> = vec![];
+    for (dir, needed) in needs {
+        let id = paths::filesystem_id(&dir).ok();
+        if let Some(known) = filesystems.iter_mut().find(|known| id.is_some() && known.0 == id) {
+            known.2 += needed;
+            continue;
+        }
+        filesystems.push((id, dir, needed));
+    }
+    for (_, dir, needed) in filesystems {
+        let free = match paths::free_space(&dir) {
+            Ok(free) => free,
+            Err(err) => {
+                println!("Couldn't check free disk space on {}: {}", dir.display(), err);
+                continue;
+            }
         };
-        println!("Applying updated file");
-        let disk_path = paths::realize_path(file.disk_path).unwrap();
-        create_dir_all(disk_path.parent().unwrap()).unwrap();
-        match exeupdate::update::update_file(disk_path, newcontent) {
-            Ok(_) => {
-                println!("File successfully updated");
-            },
+        if free < needed + FREE_SPACE_MARGIN {
+            return Err(format!("Not enough disk space: this update needs at least {} and there's only {} free on {} \
+                                (pass --no-space-check to update anyway)",
+                               ByteSize::b((needed + FREE_SPACE_MARGIN) as usize), ByteSize::b(free as usize), dir.display()));
+        }
+    }
+    Ok(())
+}
+
+/// What a stage 1 update of `files` needs room for: the decompressed files in the wineprefix, and the compressed
+/// downloads in the staging area (less whatever's already been downloaded there)
+fn stage1_space(files: &[exeupdate::File]) -> Vec<(PathBuf, u64)> {
+    let total: u64 = files.iter().map(|file| file.size).sum();
+    let staging: u64 = files.iter().map(|file| {
+        let staged = fs::metadata(exeupdate::downloader::staging_path(file)).map(|m| m.len()).unwrap_or(0);
+        file.size.saturating_sub(staged)
+    }).sum();
+    vec![(paths::plat::wineprefix(), total), (exeupdate::downloader::staging_dir(), staging)]
+}
+
+/// The files a stage 1 update needs: what's left of an interrupted update, or else whatever's out of date.
+///
+/// An interrupted update of the same wineprefix against the same file list is picked up again.
+/// Otherwise a new list is worked out and saved as the update plan, so an interrupted update can pick up where it left off.
+/// A `dry_run` works out the same files, but doesn't save or throw away anything (plan or hashes) along the way.
+fn stage1_files(matches: &clap::ArgMatches, index: String, config: &::ini::Ini, dry_run: bool) -> Vec<exeupdate::File> {
+    let target = exeupdate::plan::PlanTarget::new(paths::plat::wineprefix(), &index);
+    match exeupdate::plan::load_for(&target) {
+        Ok(Some(plan)) => {
+            println!("{} interrupted update", if dry_run {"Would resume"} else {"Resuming"});
+            // Anything pinned since the update was interrupted is left alone too
+            let (plan, pinned) = exeupdate::pins::Pins::from_config(config).partition(safe_files(plan.files));
+            print_pinned(&pinned);
+            return plan;
+        },
+        Ok(None) => {},
+        Err(stale) => {
+            match stale {
+                exeupdate::plan::Stale::OtherWineprefix(wineprefix) => {
+                    println!("Not resuming the interrupted update of {}, it was for a different wineprefix",
+                             wineprefix.display());
+                },
+                exeupdate::plan::Stale::IndexChanged => {
+                    println!("The file list has changed since the last update was interrupted, starting over");
+                }
+            }
+            if !dry_run {
+                let _ = exeupdate::plan::clear();
+            }
+        }
+    }
+    let to_update = stage1_plan(matches, index, config, dry_run);
+    if !dry_run && to_update.len() > 0 {
+        if let Err(err) = exeupdate::plan::save(&to_update, &target) {
+            println!("Failed to save update plan, an interrupted update will have to start over");
+            println!("{:?}", err);
+        }
+    }
+    to_update
+}
+
+/// Shows what `stage1_update()` would do, without downloading or writing anything to disk
+fn stage1_dry_run(matches: &clap::ArgMatches, config: &::ini::Ini) {
+    let source = content_source(matches, config, 1);
+    let (index, _) = stage1_index(&*source, config, true);
+    let to_update = stage1_files(matches, index, config, true);
+    if to_update.len() == 0 {
+        println!("Everything is up to date, nothing would be downloaded");
+        return;
+    }
+    let total_bytes: u64 = to_update.iter().map(|file| file.size).sum();
+    println!("Would download {} file{} ({} compressed):", to_update.len(), if to_update.len() != 1 {"s"} else {""},
+             ByteSize::b(total_bytes as usize));
+    for file in &to_update {
+        let dest = match paths::realize_path(file.disk_path.clone()) {
+            Some(path) => path.display().to_string(),
+            None => String::from("(nowhere, can't find the install)")
+        };
+        println!("  {:>10}  {} -> {}", format!("{}", ByteSize::b(file.size as usize)), file.disk_path, dest);
+    }
+}
+
+/// Shows what changed in the file list since the one before it, for when an update just downloaded a new one
+fn print_index_changes(config: &::ini::Ini) {
+    let (previous, current) = match (exeupdate::indexcache::load_previous(), exeupdate::indexcache::load()) {
+        (Some(previous), Some(current)) => (previous, current),
+        _ => return
+    };
+    let (previous, current) = match (exeupdate::parser::parse_file_list(previous.index),
+                                     exeupdate::parser::parse_file_list(current.index)) {
+        (Ok(previous), Ok(current)) => (previous, current),
+        _ => return
+    };
+    println!("Changes since the previous file list:");
+    super::index::print_diff(&exeupdate::diff::diff(&previous, &current), &category_rules(config));
+}
+
+/// The file categorization rules, including any from `update:rules`
+pub fn category_rules(config: &::ini::Ini) -> exeupdate::parser::Rules {
+    match exeupdate::parser::Rules::from_config(config) {
+        Ok(rules) => rules,
+        Err(err) => {
+            println!("Invalid update:rules: {}", err);
+            exit(1);
+        }
+    }
+}
+
+/// Whether a stage 1 update includes files of a given category, going by the config
+///
+/// Every category can be turned on or off with `update:<category name>`, ex `update:redist = false`.
+pub fn stage1_wants(filetype: exeupdate::FileType, config: &::ini::Ini) -> bool {
+    use exeupdate::FileType::*;
+    let default = match filetype {
+        Exe32Bit | LauncherAsset | DirectX | Driver | Redistributable | Language | Unknown => true,
+        SteamAsset | GameAsset => false,
+        Exe64Bit => config::parse_bool(config.get_from(Some("game"), "64bit"))
+    };
+    config::parse_bool_or(config.get_from(Some("update"), filetype.name()), default)
+}
+
+/// Drops file list entries with unsafe paths, letting the user know about them (see `exeupdate::validate`)
+pub fn safe_files(files: Vec<exeupdate::File>) -> Vec<exeupdate::File> {
+    let (good, rejected) = exeupdate::validate::filter_files(files);
+    for (file, err) in rejected {
+        println!("Ignoring file list entry {:?}: {}", file.disk_path, err);
+    }
+    good
+}
+
+/// Lets the user know which files an update is leaving alone because of `update:pin`
+fn print_pinned(pinned: &[exeupdate::File]) {
+    for item in pinned {
+        println!("Pinned, not updating {}", item.disk_path);
+    }
+}
+
+/// Gets the file list for a stage 1 update, from the cache if it's recent enough.
+///
+/// Also returns whether it's a new one, different from the last one downloaded.
+/// A `dry_run` doesn't cache what it downloads.
+fn stage1_index(source: &ContentSource, config: &::ini::Ini, dry_run: bool) -> (String, bool) {
+    println!("Downloading file list from {}...", source.describe());
+    let ttl = exeupdate::indexcache::ttl_from_config(config);
+    let before = exeupdate::indexcache::load();
+    let index = if dry_run {
+        exeupdate::indexcache::peek_index(source, ttl, false)
+    } else {
+        exeupdate::indexcache::get_index(source, ttl, false)
+    };
+    match index {
+        Ok((index, cached)) => {
+            if let Some(cached) = cached {
+                println!("Using file list from {} ago", describe_age(cached.age()));
+                return (index, false);
+            }
+            let changed = before.map_or(false, |before| before.index != index);
+            (index, changed)
+        },
+        Err(_) => {
+            println!("Failed to get Warframe file list. Are you connected to the internet?");
+            exit(1);
+        }
+    }
+}
+
+/// Works out which files a stage 1 update against `index` needs to download, saving the file hashes unless it's a `dry_run`
+fn stage1_plan(matches: &clap::ArgMatches, index: String, config: &::ini::Ini, dry_run: bool) -> Vec<exeupdate::File> {
+    let parsed = safe_files(super::index::parse_index(index, "the Warframe file list"));
+    println!("Checking files...");
+    let rules = category_rules(config);
+    let to_check = parsed.into_iter()
+        .filter(|item| stage1_wants(rules.categorize(item), config))
+        .collect();
+    let (to_check, pinned) = exeupdate::pins::Pins::from_config(config).partition(to_check);
+    print_pinned(&pinned);
+    check_files(to_check, matches.is_present("rehash"), false, !dry_run)
+}
+
+fn rollback(matches: &clap::ArgMatches) {
+    if matches.is_present("list") {
+        let snapshots = match exeupdate::snapshot::list() {
+            Ok(list) => list,
             Err(err) => {
-                println!("Failed to update {}", display);
+                println!("Failed to list snapshots");
                 println!("{:?}", err);
                 exit(1);
             }
         };
+        for dir in snapshots {
+            match exeupdate::snapshot::load(dir.clone()) {
+                Ok(snapshot) => {
+                    let name = dir.file_name().unwrap().to_string_lossy().into_owned();
+                    let when = ::time::at(::time::Timespec::new(name.parse().unwrap_or(0), 0));
+                    println!("{} ({} file{}, from {})", when.rfc822(), snapshot.entries.len(),
+                             if snapshot.entries.len() != 1 {"s"} else {""},
+                             snapshot.wineprefix.as_ref().map_or(String::from("an unknown wineprefix"),
+                                                                 |p| p.display().to_string()));
+                    for entry in snapshot.entries {
+                        println!("  {}", entry.disk_path);
+                    }
+                },
+                Err(err) => println!("{:?}: {:?}", dir, err)
+            }
+        }
+        return;
+    }
+
+    let wineprefix = paths::plat::wineprefix();
+    let snapshot = match exeupdate::snapshot::latest(&wineprefix) {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => {
+            println!("No snapshots of {} to roll back to", wineprefix.display());
+            exit(1);
+        },
+        Err(err) => {
+            println!("Failed to read snapshots");
+            println!("{:?}", err);
+            exit(1);
+        }
+    };
+    for entry in &snapshot.entries {
+        println!("Restoring {}", entry.disk_path);
+    }
+    match exeupdate::snapshot::restore(snapshot) {
+        Ok(_) => {
+            println!("Files successfully restored");
+            println!("Note that running a stage 1 update will update them again.");
+        },
+        Err(exeupdate::update::CommitError::NotSynced(err)) => {
+            println!("Files restored, but they might not all be safely on disk yet");
+            println!("{:?}", err);
+        },
+        Err(exeupdate::update::CommitError::RolledBack(err)) => {
+            println!("Failed to roll back, no files were changed");
+            println!("{:?}", err);
+            exit(1);
+        }
     }
 }
 
@@ -260,7 +698,8 @@ fn stage2_update(matches: &clap::ArgMatches, wfpath: PathBuf) {
             return;
         },
     };
-    match program.stdout.as_mut() {
+    let mut checked_space = false;
+    match program.stdout.take() {
         Some(out) => {
             let mut buf_reader = BufReader::new(out);
             // This is using buf_reader.read_until() and manually parsing it into lines.
@@ -288,14 +727,23 @@ fn stage2_update(matches: &clap::ArgMatches, wfpath: PathBuf) {
                     println!("{}", l);
                 }
                 let parsedline = logparser::parse_line(&*l);
-                let mut parse = true;
-                if let LogLine::Unknown(_) = parsedline {
-                    parse = false;
-                } else if let LogLine::UsedShared(_, ref name) = parsedline {
-                    if name.ends_with(".bin") {
-                        parse = false;
+                if let LogLine::BytesToDownload(bytes) = parsedline {
+                    // Running out of space halfway through corrupts the game's cache, so stop before it starts downloading.
+                    if !checked_space && !matches.is_present("nospacecheck") {
+                        if let Err(err) = enough_space(vec![(paths::plat::wineprefix(), bytes)]) {
+                            let _ = program.kill();
+                            println!("");
+                            println!("{}", err);
+                            exit(1);
+                        }
                     }
+                    checked_space = true;
                 }
+                let parse = match parsedline {
+                    LogLine::Entry(_) | LogLine::Unknown(_) => false,
+                    LogLine::UsedShared(_, ref name) => !name.ends_with(".bin"),
+                    _ => true
+                };
                 if parse {
                     parsed.push(parsedline);
                     if matches.is_present("rawlines") {
@@ -331,7 +779,7 @@ fn display_parsed(parsed: &Vec<LogLine>) -> String {
                 downloaded_bytes += size;
                 downloaded_files += 1;
             },
-            &LogLine::Unknown(_) => {}
+            &LogLine::Entry(_) | &LogLine::Unknown(_) => {}
         }
     }
 
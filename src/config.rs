@@ -15,6 +15,16 @@ pub fn set(input: Ini) {
     let _ = input.write_to_file(CONFIG_FILE_PATH.to_str().unwrap());
 }
 
+/// Gets (creating it if needed) a directory under wfupdate's XDG data dir, ex `~/.local/share/wfupdate/<name>`
+pub fn data_dir(name: &str) -> PathBuf {
+    XDG_BASEDIR.create_data_directory(name).unwrap()
+}
+
+/// Gets (creating it if needed) a directory under wfupdate's XDG cache dir, ex `~/.cache/wfupdate/<name>`
+pub fn cache_dir(name: &str) -> PathBuf {
+    XDG_BASEDIR.create_cache_directory(name).unwrap()
+}
+
 
 /// Parses a human-readable config key into a computer-friendly string
 ///
@@ -43,3 +53,40 @@ pub fn parse_bool(input: Option<&str>) -> bool {
         None | _ => false,
     }
 }
+
+/// Like `parse_bool()`, but falls back to `default` if the value is missing or isn't a boolean.
+pub fn parse_bool_or(input: Option<&str>, default: bool) -> bool {
+    match input {
+        Some("true") | Some("1") => true,
+        Some("false") | Some("0") => false,
+        None | _ => default,
+    }
+}
+
+/// Parses a number of bytes with an optional `K`, `M` or `G` suffix (powers of 1024), ex `500K` or `1.5G`.
+///
+/// A trailing `B` is allowed too, ex `2MB`. Case doesn't matter.
+pub fn parse_bytes(input: &str) -> Result<u64, String> {
+    let lower = input.trim().to_lowercase();
+    let mut number = lower.trim_right_matches('b');
+    let mut multiplier = 1f64;
+    for (i, suffix) in ["k", "m", "g"].iter().enumerate() {
+        if number.ends_with(suffix) {
+            number = number.trim_right_matches(suffix);
+            multiplier = 1024f64.powi(i as i32 + 1);
+            break;
+        }
+    }
+    match number.trim().parse::<f64>() {
+        Ok(n) if n >= 0.0 && n.is_finite() => Ok((n * multiplier) as u64),
+        _ => Err(format!("\"{}\" isn't a size, use something like 500K or 2G", input))
+    }
+}
+
+/// Parses a config value into a number, falling back to `default` if it's missing or not a number.
+pub fn parse_usize(input: Option<&str>, default: usize) -> usize {
+    match input {
+        Some(value) => value.trim().parse().unwrap_or(default),
+        None => default
+    }
+}
@@ -1,20 +1,81 @@
 //! Warframe File Checker
 //!
 //! This file is responsible for checking each file, parsed out of the Launcher index, and seeing if it needs to be updated.
+//!
+//! Files are hashed a chunk at a time, so memory use doesn't depend on how big they are,
+//! and `check_files()` spreads a list of files across every CPU core.
+//!
+//! `check_file()` skips hashing files that haven't changed since they were last hashed, see `hashcache`.
+//! Call `save_hash_cache()` once checking is done to keep those hashes for next time.
 
 use super::super::paths;
+use super::hashcache::HashCache;
 use std::io;
+use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::BufReader;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 use crypto::md5::Md5;
 use crypto::digest::Digest;
+use num_cpus;
+
+/// How much of a file is read and hashed at a time, in bytes.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+lazy_static! {
+    static ref HASH_CACHE: Mutex<HashCache> = Mutex::new(HashCache::load());
+}
+
+/// Writes out any hashes `check_file()` has worked out, for the next check to use.
+pub fn save_hash_cache() -> io::Result<()> {
+    HASH_CACHE.lock().unwrap().save()
+}
+
+/// Forgets the cached hashes of `files`, so they get hashed again on the next check.
+///
+/// The rest of the cache (other files, other wineprefixes) is left alone.
+pub fn forget_hashes(files: &[super::File]) {
+    let mut cache = HASH_CACHE.lock().unwrap();
+    for file in files {
+        if let Some(path) = paths::realize_path(file.disk_path.clone()) {
+            cache.remove(&path);
+        }
+    }
+}
+
+/// MD5 hashes everything from `reader`, `CHUNK_SIZE` bytes at a time.
+///
+/// `progress` is called with the size of each chunk once it's been hashed.
+pub fn hash_reader<R: Read, F: FnMut(u64)>(reader: &mut R, mut progress: F) -> io::Result<Vec<u8>> {
+    let mut md5 = Md5::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        md5.input(&buf[..len]);
+        progress(len as u64);
+    }
+    let mut result: [u8; 16] = [0; 16];
+    md5.result(&mut result);
+    Ok(result.to_vec())
+}
 
 /// Directly check a file, bypassing path realization.
 ///
 /// You shouldn't ever need to use this directly.
 pub fn direct_check_file(file: &super::File) -> io::Result<bool> {
-    let f = match File::open(file.disk_path.clone()) {
+    direct_check_file_with_progress(file, |_| {})
+}
+
+/// Like `direct_check_file()`, calling `progress` with the number of bytes hashed as it goes.
+pub fn direct_check_file_with_progress<F: FnMut(u64)>(file: &super::File, progress: F) -> io::Result<bool> {
+    let mut f = match File::open(file.disk_path.clone()) {
         Ok(f) => f,
         Err(e) => {
             if e.kind() == io::ErrorKind::NotFound {
@@ -24,17 +85,8 @@ pub fn direct_check_file(file: &super::File) -> io::Result<bool> {
             }
         }
     };
-    let mut md5 = Md5::new();
-    let mut reader = BufReader::new(f);
-
-    let mut buf = vec![];
-    reader.read_to_end(&mut buf);
-    md5.input(buf.as_slice());
-
-    let mut result: [u8; 16] = [0; 16];
-    md5.result(&mut result);
-
-    Ok(result != file.md5sum.as_slice())
+    let result = try!(hash_reader(&mut f, progress));
+    Ok(result != file.md5sum)
 }
 
 /// Check if a file needs to be updated.
@@ -47,31 +99,159 @@ pub fn direct_check_file(file: &super::File) -> io::Result<bool> {
 ///
 /// (see more about path realization in `paths::realize_path()`)
 ///
-/// This function performs file I/O and will be MD5 hashing the file in question.
+/// This function performs file I/O and will be MD5 hashing the file in question,
+/// unless it's the same size and has the same modification time as when it was last hashed.
 pub fn check_file(file: &super::File) -> io::Result<bool> {
-    let mut file = file.clone();
-    file.disk_path = match paths::realize_path(file.disk_path) {
-        Some(p) => p.to_str().unwrap().to_string(),
+    check_file_with_progress(file, |_| {})
+}
+
+/// Like `check_file()`, calling `progress` with the number of bytes hashed as it goes.
+pub fn check_file_with_progress<F: FnMut(u64)>(file: &super::File, progress: F) -> io::Result<bool> {
+    let path = match paths::realize_path(file.disk_path.clone()) {
+        Some(p) => p,
         None => return Ok(true)
     };
-    direct_check_file(&file)
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e)
+    };
+    if let Some(cached) = HASH_CACHE.lock().unwrap().get(&path, &metadata) {
+        return Ok(cached != file.md5sum);
+    }
+    let hash = try!(File::open(&path).and_then(|mut f| hash_reader(&mut f, progress)));
+    HASH_CACHE.lock().unwrap().insert(&path, &metadata, hash.clone());
+    Ok(hash != file.md5sum)
 }
 
 
-/// Conveience function to loop over a File vector.
+/// Checks a whole list of files, several at once.
 ///
-/// Returns a vector of the files that need to be updated
-pub fn check_files(files: Vec<super::File>) -> io::Result<Vec<super::File>> {
-    let mut needs_update = vec![];
-    for file in files {
-        match check_file(&file) {
-            Err(e) => return Err(e),
-            Ok(to_update) => {
-                if to_update {
-                    needs_update.push(file);
+/// The files are shared out between `jobs` threads, or one per CPU core if `jobs` is 0.
+/// `progress` is called from those threads with the total number of bytes hashed so far, across every file.
+///
+/// Returns the files that need to be updated, in the same order as `files`.
+/// If any file can't be checked the other threads stop after their current file, and the first error is returned.
+pub fn check_files<F>(files: Vec<super::File>, jobs: usize, progress: F) -> io::Result<Vec<super::File>>
+    where F: Fn(u64) + Send + Sync + 'static {
+    check_files_inner(files, jobs, true, progress)
+}
+
+/// `check_files()`, optionally without realizing paths (for tests, which don't have a wineprefix).
+fn check_files_inner<F>(files: Vec<super::File>, jobs: usize, realize: bool, progress: F) -> io::Result<Vec<super::File>>
+    where F: Fn(u64) + Send + Sync + 'static {
+    let jobs = if jobs == 0 { num_cpus::get() } else { jobs };
+    let queue = Arc::new(Mutex::new(files.into_iter().enumerate()));
+    let hashed = Arc::new(Mutex::new(0u64));
+    let failed = Arc::new(AtomicBool::new(false));
+    let progress = Arc::new(progress);
+    let (tx, rx) = mpsc::channel();
+    let mut workers = vec![];
+    for _ in 0..jobs {
+        let queue = queue.clone();
+        let hashed = hashed.clone();
+        let failed = failed.clone();
+        let progress = progress.clone();
+        let tx = tx.clone();
+        workers.push(thread::spawn(move || {
+            while !failed.load(Ordering::SeqCst) {
+                let (pos, file) = match queue.lock().unwrap().next() {
+                    Some(next) => next,
+                    None => break
+                };
+                let report = |bytes| {
+                    let total = {
+                        let mut hashed = hashed.lock().unwrap();
+                        *hashed += bytes;
+                        *hashed
+                    };
+                    progress(total);
+                };
+                let result = if realize {
+                    check_file_with_progress(&file, report)
+                } else {
+                    direct_check_file_with_progress(&file, report)
+                };
+                if result.is_err() {
+                    failed.store(true, Ordering::SeqCst);
                 }
+                if tx.send((pos, file, result)).is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+    drop(tx);
+
+    let mut needs_update = vec![];
+    let mut first_error = None;
+    for (pos, file, result) in rx {
+        match result {
+            Ok(true) => needs_update.push((pos, file)),
+            Ok(false) => {},
+            Err(e) => if first_error.is_none() {
+                first_error = Some(e);
             }
         }
     }
-    Ok(needs_update)
+    for worker in workers {
+        let _ = worker.join();
+    }
+    if let Some(e) = first_error {
+        return Err(e);
+    }
+    needs_update.sort_by_key(|&(pos, _)| pos);
+    Ok(needs_update.into_iter().map(|(_, file)| file).collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::downloader::md5sum;
+    use super::super::testutil::{scratch_dir, write, test_file};
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    pub fn test_hash_reader() {
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        let mut chunks = vec![];
+        let hash = hash_reader(&mut data.as_slice(), |bytes| chunks.push(bytes)).unwrap();
+        assert_eq!(hash, md5sum(data.as_slice()));
+        assert_eq!(chunks, vec![CHUNK_SIZE as u64, CHUNK_SIZE as u64, 100]);
+    }
+
+    #[test]
+    pub fn test_check_files() {
+        let dir = scratch_dir("check");
+        let mut files = vec![];
+        for i in 0..10 {
+            let path = dir.join(format!("file{}", i));
+            let content = format!("file number {}", i).into_bytes();
+            write(&path, content.as_slice());
+            let mut file = test_file(path.to_str().unwrap(), content.as_slice());
+            // every third file is out of date
+            if i % 3 == 0 {
+                file.md5sum = vec![0; 16];
+            }
+            files.push(file);
+        }
+        let expected_bytes: u64 = files.iter().map(|f| f.size).sum();
+
+        let seen = Arc::new(Mutex::new(0u64));
+        let progress = seen.clone();
+        let needs_update = check_files_inner(files.clone(), 3, false, move |total| {
+            let mut seen = progress.lock().unwrap();
+            if total > *seen {
+                *seen = total;
+            }
+        }).unwrap();
+        let names: Vec<String> = needs_update.iter().map(|f| f.disk_path.clone()).collect();
+        assert_eq!(names, vec![files[0].disk_path.clone(), files[3].disk_path.clone(),
+                               files[6].disk_path.clone(), files[9].disk_path.clone()]);
+        assert_eq!(*seen.lock().unwrap(), expected_bytes);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,330 @@
+//! Shared Download Cache
+//!
+//! Every stage 1 file that gets downloaded and verified is also kept in `$XDG_CACHE_HOME/wfupdate/files/`,
+//! named after its MD5 hash (ex `files/3B/3BB594902B2E8037901ED9B2419E8FD5`). The next time any wineprefix
+//! needs a file with that hash, it's taken from there instead of being downloaded again.
+//!
+//! Depending on `cache:mode`, files are copied between the cache and the install (`copy`, the default),
+//! hardlinked (`link`, falling back to copying when they're on different filesystems), or not cached at all (`off`).
+//!
+//! Hardlinking saves space, but it makes every install of a file *the same file*: writing over it in place in one
+//! wineprefix (ex `cp`ing a Wine-friendly DLL over `Tools/Windows/x86/d3dcompiler_47.dll`) changes it in the cache
+//! and in every other wineprefix too. That's why it isn't the default.
+//! Cached files are hashed again whenever they're used, so one that's been changed that way is at least
+//! thrown away and downloaded again instead of being installed anywhere else.
+//!
+//! The cache is kept under `cache:maxsize` by throwing away the least recently used files, see `ContentCache::gc()`.
+//! Files that are hardlinked into an install don't count, since removing them from the cache wouldn't free anything.
+
+use super::super::{config, paths};
+use super::downloader;
+use super::update;
+use hex::ToHex;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// How big the cache is allowed to get by default, in bytes
+pub const DEFAULT_MAX_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
+/// How files get from the cache to the install and back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Hardlink where possible, copy otherwise
+    Link,
+    /// Always copy
+    Copy
+}
+
+/// A directory of files named after their MD5 hashes
+#[derive(Debug, Clone)]
+pub struct ContentCache {
+    root: PathBuf,
+    mode: CacheMode
+}
+
+/// A file in the cache, for `ContentCache::entries()`
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    /// Where it is
+    pub path: PathBuf,
+    /// How big it is, in bytes
+    pub size: u64,
+    /// When it was last added or used
+    pub used: SystemTime,
+    /// Whether it's hardlinked into an install, so it doesn't take up any space of its own
+    pub shared: bool
+}
+
+/// The default cache directory, `$XDG_CACHE_HOME/wfupdate/files/`
+pub fn cache_dir() -> PathBuf {
+    config::cache_dir("files")
+}
+
+/// The cache size limit from `flag` (`--max-size`), or otherwise `cache:maxsize`
+pub fn max_size_from_config(config: &::ini::Ini, flag: Option<&str>) -> Result<u64, String> {
+    match flag.or(config.get_from(Some("cache"), "maxsize")) {
+        Some(size) => config::parse_bytes(size),
+        None => Ok(DEFAULT_MAX_SIZE)
+    }
+}
+
+impl ContentCache {
+    /// Makes a cache in `root`
+    pub fn new(root: PathBuf, mode: CacheMode) -> ContentCache {
+        ContentCache {
+            root: root,
+            mode: mode
+        }
+    }
+
+    /// The cache as set up by `cache:mode`, or `None` if it's turned off
+    pub fn from_config(config: &::ini::Ini) -> Result<Option<ContentCache>, String> {
+        let mode = match config.get_from(Some("cache"), "mode") {
+            Some("link") => CacheMode::Link,
+            None | Some("copy") => CacheMode::Copy,
+            Some("off") => return Ok(None),
+            Some(mode) => return Err(format!("cache:mode should be link, copy or off, not \"{}\"", mode))
+        };
+        Ok(Some(ContentCache::new(cache_dir(), mode)))
+    }
+
+    /// Where the file with the MD5 hash `md5sum` goes
+    pub fn path_for(&self, md5sum: &[u8]) -> PathBuf {
+        let name = md5sum.to_hex().to_uppercase();
+        self.root.join(&name[..2]).join(name)
+    }
+
+    /// Puts the cached copy of `file` in a temporary file next to `dest`, ready for `UpdateBatch::stage_temp()`.
+    ///
+    /// Returns `None` if it isn't cached, or the cached copy doesn't match its hash (in which case it's removed).
+    pub fn get(&self, file: &super::File, dest: &Path) -> Option<PathBuf> {
+        let cached = self.path_for(&file.md5sum);
+        if !cached.is_file() {
+            return None;
+        }
+        if let Some(parent) = dest.parent() {
+            if fs::create_dir_all(parent).is_err() {
+                return None;
+            }
+        }
+        let temp = match self.mode {
+            CacheMode::Link => {
+                if hash_file(&cached).ok() != Some(file.md5sum.clone()) {
+                    let _ = fs::remove_file(&cached);
+                    return None;
+                }
+                match link_temp(&cached, dest) {
+                    Ok(temp) => Some(temp),
+                    Err(_) => copy_temp(&cached, dest, &file.md5sum)
+                }
+            },
+            CacheMode::Copy => copy_temp(&cached, dest, &file.md5sum)
+        };
+        if temp.is_some() {
+            let _ = paths::touch(&cached);
+        }
+        temp
+    }
+
+    /// Adds `path`, which has already been checked to match `file`'s hash, to the cache.
+    pub fn insert(&self, file: &super::File, path: &Path) -> io::Result<()> {
+        let cached = self.path_for(&file.md5sum);
+        if cached.is_file() {
+            return paths::touch(&cached);
+        }
+        try!(fs::create_dir_all(cached.parent().unwrap()));
+        if self.mode == CacheMode::Link {
+            match fs::hard_link(path, &cached) {
+                Ok(()) => return Ok(()),
+                // Someone else just cached the same file
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(()),
+                // Probably a different filesystem, copy it instead
+                Err(_) => {}
+            }
+        }
+        let mut source = try!(File::open(path));
+        let (temp, _) = try!(update::write_temp_file_with(&cached, |f| downloader::copy_hashed(&mut source, f)));
+        if let Err(e) = fs::rename(&temp, &cached) {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Every file in the cache, least recently used first.
+    ///
+    /// Leftover temporary files are included, so `gc()` cleans them up too.
+    pub fn entries(&self) -> io::Result<Vec<CacheEntry>> {
+        let mut entries = vec![];
+        if !self.root.is_dir() {
+            return Ok(entries);
+        }
+        for dir in try!(fs::read_dir(&self.root)) {
+            let dir = try!(dir);
+            if !try!(dir.file_type()).is_dir() {
+                continue;
+            }
+            for entry in try!(fs::read_dir(dir.path())) {
+                let entry = try!(entry);
+                let metadata = try!(entry.metadata());
+                if metadata.is_file() {
+                    entries.push(CacheEntry {
+                        path: entry.path(),
+                        size: metadata.len(),
+                        used: try!(metadata.modified()),
+                        shared: metadata.nlink() > 1
+                    });
+                }
+            }
+        }
+        entries.sort_by(|a, b| a.used.cmp(&b.used));
+        Ok(entries)
+    }
+
+    /// Removes the least recently used files until the cache is no bigger than `max_size` bytes.
+    ///
+    /// Returns the removed files. Files that are hardlinked into an install (see `CacheEntry::shared`) don't count
+    /// towards the size and are left alone, since removing them wouldn't free up any space.
+    pub fn gc(&self, max_size: u64) -> io::Result<Vec<CacheEntry>> {
+        let entries: Vec<CacheEntry> = try!(self.entries()).into_iter().filter(|entry| !entry.shared).collect();
+        let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+        let mut removed = vec![];
+        for entry in entries {
+            if total <= max_size {
+                break;
+            }
+            try!(fs::remove_file(&entry.path));
+            total -= entry.size;
+            removed.push(entry);
+        }
+        Ok(removed)
+    }
+}
+
+fn hash_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut f = try!(File::open(path));
+    downloader::copy_hashed(&mut f, &mut io::sink()).map(|(_, hash)| hash)
+}
+
+/// Hardlinks `cached` to a temporary path next to `dest`
+fn link_temp(cached: &Path, dest: &Path) -> io::Result<PathBuf> {
+    // write_temp_file_with() picks an unused name, which the link then replaces
+    let (temp, _) = try!(update::write_temp_file_with(dest, |_| Ok(())));
+    let _ = fs::remove_file(&temp);
+    try!(fs::hard_link(cached, &temp));
+    Ok(temp)
+}
+
+/// Copies `cached` to a temporary file next to `dest`, checking it against `md5sum` on the way
+fn copy_temp(cached: &Path, dest: &Path, md5sum: &[u8]) -> Option<PathBuf> {
+    let mut source = match File::open(cached) {
+        Ok(f) => f,
+        Err(_) => return None
+    };
+    match update::write_temp_file_with(dest, |f| downloader::copy_hashed(&mut source, f)) {
+        Ok((temp, (_, ref hash))) if hash.as_slice() == md5sum => Some(temp),
+        Ok((temp, _)) => {
+            let _ = fs::remove_file(temp);
+            let _ = fs::remove_file(cached);
+            None
+        },
+        Err(_) => None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testutil::{scratch_dir, write, read};
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    fn test_file(content: &[u8]) -> super::super::File {
+        super::super::testutil::test_file("/Warframe.exe", content)
+    }
+
+    #[test]
+    pub fn test_cache_roundtrip() {
+        let dir = scratch_dir("contentcache");
+        let file = test_file(b"warframe");
+        for &mode in &[CacheMode::Link, CacheMode::Copy] {
+            let cache = ContentCache::new(dir.join(format!("{:?}", mode)), mode);
+            let prefix_a = dir.join("a/Warframe.exe");
+            let prefix_b = dir.join("b/Warframe.exe");
+            write(&prefix_a, b"warframe");
+
+            assert_eq!(cache.get(&file, &prefix_b), None);
+            cache.insert(&file, &prefix_a).unwrap();
+            assert_eq!(cache.path_for(&file.md5sum), dir.join(format!("{:?}", mode)).join("2C").join("2C72F878B1EE93AF3268095576A9922D"));
+            let temp = cache.get(&file, &prefix_b).unwrap();
+            assert_eq!(read(&temp), b"warframe");
+            assert_eq!(temp.parent(), prefix_b.parent());
+            let links = fs::metadata(&temp).unwrap().nlink();
+            assert_eq!(links, if mode == CacheMode::Link { 3 } else { 1 });
+            fs::remove_file(temp).unwrap();
+            fs::remove_file(prefix_a).unwrap();
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_cache_corrupt() {
+        let dir = scratch_dir("contentcache");
+        let file = test_file(b"warframe");
+        let cache = ContentCache::new(dir.join("cache"), CacheMode::Link);
+        write(&cache.path_for(&file.md5sum), b"changed in place");
+        assert_eq!(cache.get(&file, &dir.join("a/Warframe.exe")), None);
+        assert!(!cache.path_for(&file.md5sum).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_cache_gc() {
+        let dir = scratch_dir("contentcache");
+        let cache = ContentCache::new(dir.join("cache"), CacheMode::Copy);
+        let old = test_file(b"old");
+        let new = test_file(b"newer");
+        write(&dir.join("old"), b"old");
+        write(&dir.join("new"), b"newer");
+        cache.insert(&old, &dir.join("old")).unwrap();
+        cache.insert(&new, &dir.join("new")).unwrap();
+        // Make sure "old" really is older, some filesystems only keep whole seconds
+        let mut used = fs::metadata(cache.path_for(&old.md5sum)).unwrap().modified().unwrap();
+        while fs::metadata(cache.path_for(&new.md5sum)).unwrap().modified().unwrap() <= used {
+            ::std::thread::sleep(::std::time::Duration::from_millis(50));
+            paths::touch(&cache.path_for(&new.md5sum)).unwrap();
+            used = fs::metadata(cache.path_for(&old.md5sum)).unwrap().modified().unwrap();
+        }
+
+        assert_eq!(cache.gc(8).unwrap().len(), 0);
+        let removed = cache.gc(5).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].path, cache.path_for(&old.md5sum));
+        assert!(cache.path_for(&new.md5sum).exists());
+        assert_eq!(cache.gc(0).unwrap().len(), 1);
+        assert_eq!(cache.entries().unwrap().len(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_cache_gc_skips_shared() {
+        let dir = scratch_dir("contentcache");
+        let cache = ContentCache::new(dir.join("cache"), CacheMode::Link);
+        let file = test_file(b"installed");
+        write(&dir.join("a/Warframe.exe"), b"installed");
+        cache.insert(&file, &dir.join("a/Warframe.exe")).unwrap();
+        assert!(cache.entries().unwrap()[0].shared);
+        // It doesn't take up any space of its own, so there's nothing to free
+        assert_eq!(cache.gc(0).unwrap().len(), 0);
+        assert!(cache.path_for(&file.md5sum).exists());
+
+        fs::remove_file(dir.join("a/Warframe.exe")).unwrap();
+        assert_eq!(cache.gc(0).unwrap().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+//! Stage 1 Index Diffing
+//!
+//! Compares two parsed indexes and works out which files were added, removed, or changed hash between them.
+//! Used by `wfupdate index diff`, and to show what a stage 1 update actually touched.
+
+use super::parser::{FileType, Rules};
+use std::collections::HashMap;
+
+/// The differences between two indexes.
+///
+/// Everything is sorted by `disk_path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexDiff {
+    /// Files only in the new index
+    pub added: Vec<super::File>,
+    /// Files only in the old index
+    pub removed: Vec<super::File>,
+    /// Files in both, with different hashes, as (old, new)
+    pub changed: Vec<(super::File, super::File)>
+}
+
+impl IndexDiff {
+    /// True if the two indexes had the same files with the same hashes
+    pub fn is_empty(&self) -> bool {
+        self.added.len() == 0 && self.removed.len() == 0 && self.changed.len() == 0
+    }
+
+    /// How much bigger (or smaller) the new index's compressed files are in total, in bytes.
+    pub fn size_delta(&self) -> i64 {
+        let mut delta = 0i64;
+        for file in &self.added {
+            delta += file.size as i64;
+        }
+        for file in &self.removed {
+            delta -= file.size as i64;
+        }
+        for &(ref old, ref new) in &self.changed {
+            delta += new.size as i64 - old.size as i64;
+        }
+        delta
+    }
+
+    /// Splits the diff up by file category, leaving out categories with no changes.
+    pub fn by_type(&self, rules: &Rules) -> Vec<(FileType, IndexDiff)> {
+        let categorize = |f: &super::File| rules.categorize(f);
+        let mut out = vec![];
+        for filetype in FileType::all() {
+            let part = IndexDiff {
+                added: self.added.iter().filter(|f| categorize(f) == filetype).cloned().collect(),
+                removed: self.removed.iter().filter(|f| categorize(f) == filetype).cloned().collect(),
+                changed: self.changed.iter().filter(|&&(_, ref f)| categorize(f) == filetype).cloned().collect()
+            };
+            if !part.is_empty() {
+                out.push((filetype, part));
+            }
+        }
+        out
+    }
+}
+
+/// Compares two indexes, matching files up by `disk_path`.
+pub fn diff(old: &[super::File], new: &[super::File]) -> IndexDiff {
+    let old_files: HashMap<&str, &super::File> = old.iter().map(|f| (f.disk_path.as_str(), f)).collect();
+    let new_files: HashMap<&str, &super::File> = new.iter().map(|f| (f.disk_path.as_str(), f)).collect();
+    let mut out = IndexDiff {
+        added: vec![],
+        removed: vec![],
+        changed: vec![]
+    };
+    for file in new {
+        match old_files.get(file.disk_path.as_str()) {
+            Some(old) => if old.md5sum != file.md5sum {
+                out.changed.push(((*old).clone(), file.clone()));
+            },
+            None => out.added.push(file.clone())
+        }
+    }
+    for file in old {
+        if !new_files.contains_key(file.disk_path.as_str()) {
+            out.removed.push(file.clone());
+        }
+    }
+    out.added.sort_by(|a, b| a.disk_path.cmp(&b.disk_path));
+    out.removed.sort_by(|a, b| a.disk_path.cmp(&b.disk_path));
+    out.changed.sort_by(|a, b| a.1.disk_path.cmp(&b.1.disk_path));
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::parser::{FileType, Rules};
+    use super::super::testutil::{file_list, SAMPLE_INDEX};
+
+    #[test]
+    pub fn test_diff() {
+        let old = file_list(SAMPLE_INDEX);
+        let new = file_list("/Tools/Launcher.exe.F336FD22FDF21024C75FF46FE8F7A06E.lzma,313612\r\n\
+                             /Tools/Windows/x86/msvcr110.dll.4BA25D2CBE1587A841DCFB8C8C4A6EA6.lzma,351188\r\n\
+                             /Warframe.exe.81E34ABBF3AEFAD7E56D157EDE08E178.lzma,7000000\r\n");
+        let result = diff(&old, &new);
+        assert_eq!(result.added.len(), 1);
+        assert_eq!(result.added[0].disk_path, "/Tools/Windows/x86/msvcr110.dll");
+        assert_eq!(result.removed.len(), 1);
+        assert_eq!(result.removed[0].disk_path, "/Tools/Windows/x86/symsrv.dll");
+        assert_eq!(result.changed.len(), 1);
+        assert_eq!(result.changed[0].1.disk_path, "/Warframe.exe");
+        assert_eq!(result.size_delta(), 351188 - 47951 + (7000000 - 6998205));
+
+        let grouped = result.by_type(&Rules::default());
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped[0].0, FileType::Exe32Bit);
+        assert_eq!(grouped[0].1.changed.len(), 1);
+        assert_eq!(grouped[1].0, FileType::Redistributable);
+        assert_eq!(grouped[1].1.added.len(), 1);
+
+        assert!(diff(&new, &new).is_empty());
+    }
+}
@@ -1,17 +1,42 @@
 //! Warframe Asset Downloader
 //!
 //! Handles getting and decompressing data from Warframe content servers.
+//! Everything is read through a `source::ContentSource`, which is usually origin.warframe.com but can be a local mirror.
 //!
 //! `get_file()` is the main function here.
+//!
+//! Stage 1 files are downloaded with `get_verified_file()`, which writes the compressed `.lzma` into a staging area
+//! (`$XDG_CACHE_HOME/wfupdate/staging/`) first. If a download gets interrupted, the next attempt picks up
+//! where it left off (using an HTTP `Range` request for web servers) instead of starting over.
+//! The same goes for a download that breaks off partway through (a stall or a dropped connection),
+//! which is resumed after a short wait, from the next server if there's more than one.
+//! The staged file is then decompressed straight into a temp file next to where it's going, and hashed on the way,
+//! so memory use stays the same no matter how big the file is.
+//!
+//! `get_verified_files()` runs several of those downloads at once over a shared source,
+//! and can take files from (and add them to) the shared download cache in `contentcache`.
 
 
-use rand::Rng;
 use lzma;
 use hyper;
-use hyper::Client;
-use std::io::Read;
+use hyper::status::StatusCode;
+use std::io;
+use std::io::{Read, Write, BufReader};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::cmp;
+use crypto::md5::Md5;
+use crypto::digest::Digest;
+use super::super::config;
+use super::source::ContentSource;
+use super::contentcache::ContentCache;
+use super::update;
 
-static WARFRAME_CONTENT_URL: &'static str = "http://origin.warframe.com/";
+/// How many times `get_verified_file()` will try to download a file before giving up.
+pub const DOWNLOAD_ATTEMPTS: usize = 3;
 
 /// A general purpose error type for get_file.
 ///
@@ -22,42 +47,69 @@ pub enum DownloaderError {
     HyperError(hyper::Error),
     /// Error circa lzma (failed to decode, etc)
     LZMAError(lzma::Error),
-    /// IO error. Currently can only occur from lzma.
-    IOError(::std::io::Error)
+    /// IO error, from lzma or from writing to the staging area.
+    IOError(::std::io::Error),
+    /// The server responded with something other than the file (404, 500, etc)
+    HttpStatus(StatusCode),
+    /// The decompressed file didn't match the MD5 hash from the index, even after retrying.
+    ///
+    /// Contains the expected hash and the hash of the last attempt.
+    HashMismatch(Vec<u8>, Vec<u8>)
+}
+
+impl DownloaderError {
+    /// Whether the file just isn't there, ex a 404 or a file missing from a local mirror
+    pub fn is_not_found(&self) -> bool {
+        match *self {
+            DownloaderError::HttpStatus(StatusCode::NotFound) => true,
+            DownloaderError::IOError(ref err) => err.kind() == io::ErrorKind::NotFound,
+            _ => false
+        }
+    }
+}
+
+impl From<io::Error> for DownloaderError {
+    fn from(err: io::Error) -> DownloaderError {
+        DownloaderError::IOError(err)
+    }
 }
 
 /// Gets the Warframe launcher file list and parses it into a String.
 ///
-/// This is just a conveience function to calling `get_file` with the right URL
+/// This is just a conveience function to calling `get_file` with the source's index path
 /// and then `String::from_utf8`ing it.
 ///
-/// The URL in question is `http://origin.warframe.com/origin/XXXXXXXX/index.txt.lzma`,
+/// For origin.warframe.com the URL in question is `http://origin.warframe.com/origin/XXXXXXXX/index.txt.lzma`,
 /// where `XXXXXXXX` is eight random hexadecimal characters.
-pub fn get_index() -> Result<String, DownloaderError> {
-    let mut rng = ::rand::thread_rng();
-    match get_file(format!("origin/{:08X}/index.txt.lzma", rng.gen::<u32>())) {
+pub fn get_index(source: &ContentSource) -> Result<String, DownloaderError> {
+    match get_file(source, source.index_path()) {
         Ok(body) => Ok(String::from_utf8(body).unwrap()),
         Err(e) => Err(e)
     }
 }
 
-/// Retrieves a file from Warframe servers, decompresses it, and returns it as a u8 vector.
+/// Retrieves a file from a content source, decompresses it, and returns it as a u8 vector.
+///
+/// The whole file ends up in memory, so this is only meant for small things like the index.
+/// Use `get_verified_file()` for anything that's going on disk.
 ///
 /// ## Example
 /// ```rust,no_run
-/// let result = get_file("/Tools/Launcher.exe.F336FD22FDF21024C75FF46FE8F7A06E.lzma".to_string()).unwrap();
+/// let source = source::HttpSource::new(source::WARFRAME_CONTENT_URL, 1);
+/// let result = get_file(&source, "/Tools/Launcher.exe.F336FD22FDF21024C75FF46FE8F7A06E.lzma".to_string()).unwrap();
 /// ```
 /// This will issue a request for `http://origin.warframe.com/Tools/Launcher.exe.F336FD22FDF21024C75FF46FE8F7A06E.lzma`,
 /// download the file, LZMA decode it, and return it as a Vec<u8>.
-pub fn get_file(path: String) -> Result<Vec<u8>, DownloaderError> {
-    let client = Client::new();
-    let res = match client.get(format!("{}{}", WARFRAME_CONTENT_URL, path).as_str()).send() {
-        Ok(res) => res,
-        Err(err) => return Err(DownloaderError::HyperError(err))
-    };
+pub fn get_file(source: &ContentSource, path: String) -> Result<Vec<u8>, DownloaderError> {
+    let res = try!(source.open(path.as_str(), 0));
+    decompress(res)
+}
+
+/// LZMA decodes everything from `reader` into a u8 vector.
+pub fn decompress<R: Read>(reader: R) -> Result<Vec<u8>, DownloaderError> {
     let mut body = vec![];
 
-    let mut decoder = match lzma::read(res) {
+    let mut decoder = match lzma::read(reader) {
         Ok(decoder) => decoder,
         Err(err) => return Err(DownloaderError::LZMAError(err))
     };
@@ -67,3 +119,491 @@ pub fn get_file(path: String) -> Result<Vec<u8>, DownloaderError> {
 
     Ok(body)
 }
+
+/// LZMA decodes everything from `reader` without keeping it, and returns the MD5 hash of the decompressed data.
+pub fn hash_compressed<R: Read>(reader: R) -> Result<Vec<u8>, DownloaderError> {
+    let mut decoder = match lzma::read(reader) {
+        Ok(decoder) => decoder,
+        Err(err) => return Err(DownloaderError::LZMAError(err))
+    };
+    let (_, hash) = try!(copy_hashed(&mut decoder, &mut io::sink()));
+    Ok(hash)
+}
+
+/// LZMA decodes everything from `reader` into a new temporary file next to `dest`.
+///
+/// Returns the temporary file's path and the MD5 hash of the decompressed data.
+/// Only a small buffer's worth of the file is ever in memory at once.
+pub fn decompress_to<R: Read>(reader: R, dest: &Path) -> Result<(PathBuf, Vec<u8>), DownloaderError> {
+    let mut decoder = match lzma::read(reader) {
+        Ok(decoder) => decoder,
+        Err(err) => return Err(DownloaderError::LZMAError(err))
+    };
+    let (temp, (_, hash)) = try!(update::write_temp_file_with(dest, |f| copy_hashed(&mut decoder, f)));
+    Ok((temp, hash))
+}
+
+/// The directory partial and finished-but-not-yet-applied downloads are kept in
+pub fn staging_dir() -> PathBuf {
+    config::cache_dir("staging")
+}
+
+/// Where the compressed download for `file` is kept in the staging area
+///
+/// The name is the whole download path with `/`s escaped, since the same file name can be in more than one directory
+/// (ex the 32 and 64 bit copies of a DLL).
+pub fn staging_path(file: &super::File) -> PathBuf {
+    let name = file.download_path.trim_left_matches('/').replace("%", "%25").replace("/", "%2F");
+    staging_dir().join(name)
+}
+
+/// Deletes everything in the staging area, except for the update plan.
+pub fn clear_staging() -> io::Result<()> {
+    for entry in try!(fs::read_dir(staging_dir())) {
+        let entry = try!(entry);
+        if entry.file_name().to_string_lossy().ends_with(".lzma") {
+            try!(fs::remove_file(entry.path()));
+        }
+    }
+    Ok(())
+}
+
+/// Copies `path` from `source` into `dest`, without decompressing it.
+///
+/// If `dest` already has some data in it, only the rest of the file is read.
+pub fn download_to(source: &ContentSource, path: &str, dest: &Path) -> Result<(), DownloaderError> {
+    let existing = match fs::metadata(dest) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0
+    };
+    let mut res = try!(source.open(path, existing));
+    match append_body(&mut res, dest) {
+        Ok(()) => Ok(()),
+        Err(CopyError::BrokenOff(err)) | Err(CopyError::Local(err)) => Err(DownloaderError::IOError(err))
+    }
+}
+
+/// How copying a download into the staging area went wrong
+enum CopyError {
+    /// Reading from the source failed, the download broke off partway through
+    BrokenOff(io::Error),
+    /// Writing to the staged file failed
+    Local(io::Error)
+}
+
+/// Adds everything from `reader` to the end of `dest`
+fn append_body(reader: &mut Read, dest: &Path) -> Result<(), CopyError> {
+    let mut out = try!(OpenOptions::new().create(true).append(true).open(dest).map_err(CopyError::Local));
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(CopyError::BrokenOff(e))
+        };
+        try!(out.write_all(&buf[..len]).map_err(CopyError::Local));
+    }
+    out.sync_all().map_err(CopyError::Local)
+}
+
+/// Retrieves a file from a content source and checks it against the hash from the index.
+///
+/// The compressed file is downloaded into the staging area (resuming a previous partial download if there is one),
+/// then decompressed and MD5 hashed and compared to `file.md5sum`.
+/// Downloads that don't match (usually truncated responses) are thrown away and retried, up to `DOWNLOAD_ATTEMPTS` times in total.
+/// If every attempt comes back wrong, `DownloaderError::HashMismatch` is returned.
+/// A download that breaks off partway through (reading from the source fails after it was opened) is picked up again
+/// from where it stopped, for as long as the source's `retry_delay()` allows. That doesn't count as an attempt.
+/// Failing to open the file at all isn't retried here, sources that can retry do that themselves.
+///
+/// The decompressed file is written to a temporary file next to `dest` (see `update::write_temp_file()`),
+/// and its path returned, ready for `UpdateBatch::stage_temp()`. `dest`'s directory is created if it doesn't exist.
+///
+/// The staged `.lzma` is left in place, call `clear_staging()` once the update has been applied.
+pub fn get_verified_file(source: &ContentSource, file: &super::File, dest: &Path) -> Result<PathBuf, DownloaderError> {
+    fetch_verified(source, file, &staging_path(file), dest)
+}
+
+/// A file from `get_verified_files()`, along with how downloading it went.
+///
+/// On success, the path is the temporary file the download was decompressed into.
+pub type DownloadResult = (super::File, Result<PathBuf, DownloaderError>);
+
+/// Downloads and verifies several files at once, like `get_verified_file()`.
+///
+/// `files` pairs each file with where it's going on disk.
+/// Up to `jobs` worker threads share `source` (for HTTP sources, that means connections to the server get reused).
+/// Results are sent back over the returned channel as each file finishes, in whatever order that happens.
+/// The channel only buffers `jobs` results, so if the receiver falls behind the workers wait for it.
+/// Dropping the receiver stops the workers after their current file, and they delete its temp file.
+/// Results already sitting in the channel are the receiver's to clean up, see `discard_downloads()`.
+///
+/// With a `cache`, files already in it aren't downloaded at all, and everything that is downloaded gets added to it.
+pub fn get_verified_files(source: Arc<ContentSource>, files: Vec<(super::File, PathBuf)>, jobs: usize,
+                          cache: Option<ContentCache>) -> mpsc::Receiver<DownloadResult> {
+    let jobs = cmp::max(jobs, 1);
+    let queue = Arc::new(Mutex::new(files.into_iter()));
+    let (tx, rx) = mpsc::sync_channel(jobs);
+    for _ in 0..jobs {
+        let source = source.clone();
+        let queue = queue.clone();
+        let tx = tx.clone();
+        let cache = cache.clone();
+        thread::spawn(move || {
+            loop {
+                let (file, dest) = match queue.lock().unwrap().next() {
+                    Some(next) => next,
+                    None => break
+                };
+                let result = match cache.as_ref().and_then(|cache| cache.get(&file, &dest)) {
+                    Some(temp) => Ok(temp),
+                    None => {
+                        let result = fetch_verified(&*source, &file, &staging_path(&file), &dest);
+                        if let (Some(cache), &Ok(ref temp)) = (cache.as_ref(), &result) {
+                            // Not being able to cache it doesn't stop the update
+                            let _ = cache.insert(&file, temp);
+                        }
+                        result
+                    }
+                };
+                if let Err(mpsc::SendError((_, result))) = tx.send((file, result)) {
+                    if let Ok(temp) = result {
+                        let _ = fs::remove_file(temp);
+                    }
+                    break;
+                }
+            }
+        });
+    }
+    rx
+}
+
+/// Deletes the temp files of any results that have already finished but not been received yet.
+///
+/// For giving up on a `get_verified_files()` partway through without leaving temp files next to the game files.
+/// Downloads still in progress when this is called aren't waited for.
+pub fn discard_downloads(downloads: mpsc::Receiver<DownloadResult>) {
+    while let Ok((_, result)) = downloads.try_recv() {
+        if let Ok(temp) = result {
+            let _ = fs::remove_file(temp);
+        }
+    }
+}
+
+fn fetch_verified(source: &ContentSource, file: &super::File, staged: &Path, dest: &Path) -> Result<PathBuf, DownloaderError> {
+    if let Some(parent) = dest.parent() {
+        try!(fs::create_dir_all(parent));
+    }
+    let mut last_error = None;
+    let mut attempts = 0;
+    let mut broken_off = 0;
+    while attempts < DOWNLOAD_ATTEMPTS {
+        let have = match fs::metadata(staged) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0
+        };
+        if have < file.size {
+            let mut res = try!(source.open(file.download_path.as_str(), have));
+            match append_body(&mut res, staged) {
+                Ok(()) => {},
+                Err(CopyError::Local(err)) => return Err(DownloaderError::IOError(err)),
+                Err(CopyError::BrokenOff(err)) => {
+                    broken_off += 1;
+                    match source.retry_delay(broken_off) {
+                        // What was downloaded so far is still staged, so the next time round carries on from there
+                        Some(delay) => thread::sleep(delay),
+                        None => return Err(DownloaderError::IOError(err))
+                    }
+                    continue;
+                }
+            }
+        }
+        attempts += 1;
+        let decompressed = File::open(staged).map_err(DownloaderError::from)
+            .and_then(|f| decompress_to(BufReader::new(f), dest));
+        let (temp, actual) = match decompressed {
+            Ok(decompressed) => decompressed,
+            Err(err) => {
+                // Most likely a corrupt or truncated download. Start this one over from scratch.
+                let _ = fs::remove_file(staged);
+                last_error = Some(err);
+                continue;
+            }
+        };
+        if actual == file.md5sum {
+            return Ok(temp);
+        }
+        let _ = fs::remove_file(temp);
+        let _ = fs::remove_file(staged);
+        last_error = Some(DownloaderError::HashMismatch(file.md5sum.clone(), actual));
+    }
+    Err(last_error.unwrap())
+}
+
+/// MD5 hashes a byte slice, returning the hash as a u8 vector.
+pub fn md5sum(data: &[u8]) -> Vec<u8> {
+    let mut md5 = Md5::new();
+    md5.input(data);
+    let mut result: [u8; 16] = [0; 16];
+    md5.result(&mut result);
+    result.to_vec()
+}
+
+/// Copies everything from `reader` into `writer`, MD5 hashing it on the way through.
+///
+/// Returns how many bytes were copied and their hash.
+pub fn copy_hashed<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<(u64, Vec<u8>)> {
+    let mut md5 = Md5::new();
+    let mut buf = [0u8; 64 * 1024];
+    let mut total = 0u64;
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        md5.input(&buf[..len]);
+        try!(writer.write_all(&buf[..len]));
+        total += len as u64;
+    }
+    let mut result: [u8; 16] = [0; 16];
+    md5.result(&mut result);
+    Ok((total, result.to_vec()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::source::{HttpSource, DirectorySource};
+    use super::super::network::{NetworkConfig, RetryPolicy};
+    use super::super::testutil::{scratch_dir, write, read, test_content, TEST_LZMA};
+    use hyper::server::{Server, Request, Response, Listening};
+    use hyper::header::{Range, ByteRangeSpec};
+    use hyper::status::StatusCode;
+    use std::fs;
+    use std::io::prelude::*;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    /// A tiny stand-in for origin.warframe.com, serving `TEST_LZMA` at every path.
+    ///
+    /// Returns the server, its base URL, and a log of the `Range` start offsets it was asked for.
+    fn serve() -> (Listening, String, Arc<Mutex<Vec<u64>>>) {
+        let ranges = Arc::new(Mutex::new(vec![]));
+        let log = ranges.clone();
+        let listening = Server::http("127.0.0.1:0").unwrap().handle(move |req: Request, mut res: Response| {
+            let start = match req.headers.get::<Range>() {
+                Some(&Range::Bytes(ref specs)) => match specs.first() {
+                    Some(&ByteRangeSpec::AllFrom(start)) => start,
+                    _ => 0
+                },
+                _ => 0
+            };
+            log.lock().unwrap().push(start);
+            if start > 0 {
+                *res.status_mut() = StatusCode::PartialContent;
+            }
+            res.send(&TEST_LZMA[start as usize..]).unwrap();
+        }).unwrap();
+        let base = format!("http://{}/", listening.socket);
+        (listening, base, ranges)
+    }
+
+    /// A server that promises all of `TEST_LZMA` but hangs up after the first 20 bytes, every time.
+    ///
+    /// Returns its base URL and how many requests it's had.
+    fn broken_server() -> (String, Arc<Mutex<usize>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(0));
+        let count = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                // Read the request up to the blank line at the end of the headers
+                for line in BufReader::new(&stream).lines() {
+                    if line.unwrap().len() == 0 {
+                        break;
+                    }
+                }
+                *count.lock().unwrap() += 1;
+                write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", TEST_LZMA.len()).unwrap();
+                stream.write_all(&TEST_LZMA[..20]).unwrap();
+            }
+        });
+        (base, requests)
+    }
+
+    fn test_file() -> super::super::File {
+        let mut file = super::super::testutil::test_file("/Warframe.exe", test_content().as_slice());
+        file.size = TEST_LZMA.len() as u64;
+        file
+    }
+
+    #[test]
+    pub fn test_staging_path() {
+        let x86 = super::super::testutil::test_file("/Tools/Windows/x86/d3dx9_43.dll", b"d3dx9");
+        let x64 = super::super::testutil::test_file("/Tools/Windows/x64/d3dx9_43.dll", b"d3dx9");
+        assert!(staging_path(&x86) != staging_path(&x64));
+        assert_eq!(staging_path(&x86).parent(), Some(staging_dir().as_path()));
+        let file = test_file();
+        assert_eq!(staging_path(&file), staging_dir().join(file.download_path.trim_left_matches('/')));
+    }
+
+    #[test]
+    pub fn test_resume_download() {
+        let (mut listening, base, ranges) = serve();
+        let dir = scratch_dir("resume");
+        let dest = dir.join("Warframe.exe.lzma");
+        write(&dest, &TEST_LZMA[..30]);
+
+        download_to(&HttpSource::new(base.as_str(), 1), "Warframe.exe.lzma", &dest).unwrap();
+        assert_eq!(read(&dest).as_slice(), TEST_LZMA);
+        assert_eq!(*ranges.lock().unwrap(), vec![30]);
+
+        fs::remove_dir_all(dir).unwrap();
+        listening.close().unwrap();
+    }
+
+    #[test]
+    pub fn test_fetch_verified() {
+        let (mut listening, base, ranges) = serve();
+        let dir = scratch_dir("verified");
+        let staged = dir.join("Warframe.exe.lzma");
+        // A partial download from an earlier, interrupted run
+        write(&staged, &TEST_LZMA[..10]);
+        let dest = dir.join("Warframe.exe");
+
+        let temp = fetch_verified(&HttpSource::new(base.as_str(), 1), &test_file(), &staged, &dest).unwrap();
+        assert_eq!(read(&temp), test_content());
+        assert_eq!(temp.parent(), dest.parent());
+        assert!(!dest.exists());
+        assert_eq!(*ranges.lock().unwrap(), vec![10]);
+
+        fs::remove_dir_all(dir).unwrap();
+        listening.close().unwrap();
+    }
+
+    #[test]
+    pub fn test_fetch_verified_resumes_broken_download() {
+        let (broken, broken_requests) = broken_server();
+        let (mut listening, base, ranges) = serve();
+        let network = NetworkConfig {
+            retry: RetryPolicy {
+                attempts: 3,
+                backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(50)
+            },
+            ..NetworkConfig::default()
+        };
+        let source = HttpSource::with_config(vec![broken.as_str(), base.as_str()], 1, network).unwrap();
+        let dir = scratch_dir("broken");
+        let staged = dir.join("Warframe.exe.lzma");
+        let dest = dir.join("Warframe.exe");
+
+        let temp = fetch_verified(&source, &test_file(), &staged, &dest).unwrap();
+        assert_eq!(read(&temp), test_content());
+        // The broken server got one go, and the rest came from the other one
+        assert_eq!(*broken_requests.lock().unwrap(), 1);
+        assert_eq!(*ranges.lock().unwrap(), vec![20]);
+
+        // With only the broken server, it gives up once it's out of retries
+        let source = HttpSource::with_config(vec![broken.as_str()], 1, NetworkConfig {
+            retry: RetryPolicy {
+                attempts: 2,
+                backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(50)
+            },
+            ..NetworkConfig::default()
+        }).unwrap();
+        let other = dir.join("Warframe.exe.again.lzma");
+        assert!(fetch_verified(&source, &test_file(), &other, &dest).is_err());
+        assert_eq!(*broken_requests.lock().unwrap(), 3);
+
+        fs::remove_dir_all(dir).unwrap();
+        drop(source);
+        listening.close().unwrap();
+    }
+
+    #[test]
+    pub fn test_fetch_verified_leaves_open_retries_to_the_source() {
+        let requests = Arc::new(Mutex::new(0));
+        let count = requests.clone();
+        let mut listening = Server::http("127.0.0.1:0").unwrap().handle(move |_: Request, mut res: Response| {
+            *count.lock().unwrap() += 1;
+            *res.status_mut() = StatusCode::ServiceUnavailable;
+            res.send(b"").unwrap();
+        }).unwrap();
+        let base = format!("http://{}/", listening.socket);
+        let source = HttpSource::with_config(vec![base.as_str()], 1, NetworkConfig {
+            retry: RetryPolicy {
+                attempts: 3,
+                backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(50)
+            },
+            ..NetworkConfig::default()
+        }).unwrap();
+        let dir = scratch_dir("unavailable");
+        let staged = dir.join("Warframe.exe.lzma");
+        let dest = dir.join("Warframe.exe");
+
+        match fetch_verified(&source, &test_file(), &staged, &dest) {
+            Err(DownloaderError::HttpStatus(StatusCode::ServiceUnavailable)) => {},
+            other => panic!("expected a 503, got {:?}", other)
+        }
+        // Only the source's own tries, nothing on top
+        assert_eq!(*requests.lock().unwrap(), 3);
+
+        fs::remove_dir_all(dir).unwrap();
+        drop(source);
+        listening.close().unwrap();
+    }
+
+    #[test]
+    pub fn test_fetch_verified_from_directory() {
+        let dir = scratch_dir("directory");
+        let mirror = dir.join("mirror");
+        let file = test_file();
+        write(&mirror.join(file.download_path.trim_left_matches('/')), TEST_LZMA);
+        let staged = dir.join("Warframe.exe.lzma");
+        let dest = dir.join("Warframe.exe");
+
+        let temp = fetch_verified(&DirectorySource::new(mirror.clone()), &file, &staged, &dest).unwrap();
+        assert_eq!(read(&temp), test_content());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_fetch_verified_mismatch() {
+        let (mut listening, base, ranges) = serve();
+        let dir = scratch_dir("mismatch");
+        let staged = dir.join("Warframe.exe.lzma");
+        let dest_dir = dir.join("dest");
+        let dest = dest_dir.join("Warframe.exe");
+        let mut file = test_file();
+        file.md5sum = vec![0; 16];
+
+        match fetch_verified(&HttpSource::new(base.as_str(), 1), &file, &staged, &dest) {
+            Err(DownloaderError::HashMismatch(expected, actual)) => {
+                assert_eq!(expected, vec![0; 16]);
+                assert_eq!(actual, md5sum(test_content().as_slice()));
+            },
+            other => panic!("expected a hash mismatch, got {:?}", other)
+        }
+        // Every attempt should have started over from scratch
+        assert_eq!(*ranges.lock().unwrap(), vec![0, 0, 0]);
+        assert!(!staged.exists());
+        // and no temp files left behind
+        assert_eq!(fs::read_dir(&dest_dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(dir).unwrap();
+        listening.close().unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+//! File Hash Cache
+//!
+//! Remembers the MD5 of every file `checker` has hashed, so an unchanged file doesn't get read again on the next check.
+//! Entries are keyed by the realized path, and only count as long as the file's size and modification time still match.
+//!
+//! The cache lives at `$XDG_CACHE_HOME/wfupdate/hashes/hashes.txt`, one `size,mtime,nanoseconds,HASH,path` line per file.
+//! Lines that don't parse are skipped, so a damaged cache just means some files get hashed again.
+
+use super::super::config;
+use super::update;
+use hex::{FromHex, ToHex};
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Where the hash cache is kept
+pub fn cache_path() -> PathBuf {
+    config::cache_dir("hashes").join("hashes.txt")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    size: u64,
+    mtime: (u64, u32),
+    md5sum: Vec<u8>
+}
+
+/// A file's modification time as (seconds, nanoseconds) since the epoch, if the platform has one.
+fn mtime(metadata: &fs::Metadata) -> Option<(u64, u32)> {
+    metadata.modified().ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| (since.as_secs(), since.subsec_nanos()))
+}
+
+/// A set of cached file hashes
+#[derive(Debug)]
+pub struct HashCache {
+    path: PathBuf,
+    entries: HashMap<PathBuf, Entry>,
+    dirty: bool
+}
+
+impl HashCache {
+    /// Loads the hash cache from `cache_path()`
+    pub fn load() -> HashCache {
+        HashCache::load_from(cache_path())
+    }
+
+    /// Loads a hash cache from `path`, starting out empty if there's nothing there.
+    pub fn load_from(path: PathBuf) -> HashCache {
+        let mut entries = HashMap::new();
+        if let Ok(f) = File::open(&path) {
+            for line in BufReader::new(f).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break
+                };
+                let parts: Vec<&str> = line.splitn(5, ',').collect();
+                if parts.len() != 5 {
+                    continue;
+                }
+                let entry = match (parts[0].parse(), parts[1].parse(), parts[2].parse(), Vec::from_hex(parts[3])) {
+                    (Ok(size), Ok(secs), Ok(nanos), Ok(md5sum)) => Entry {
+                        size: size,
+                        mtime: (secs, nanos),
+                        md5sum: md5sum
+                    },
+                    _ => continue
+                };
+                entries.insert(PathBuf::from(parts[4]), entry);
+            }
+        }
+        HashCache {
+            path: path,
+            entries: entries,
+            dirty: false
+        }
+    }
+
+    /// The cached hash of `path`, if it's been hashed before and hasn't changed since.
+    pub fn get(&self, path: &Path, metadata: &fs::Metadata) -> Option<Vec<u8>> {
+        match (self.entries.get(path), mtime(metadata)) {
+            (Some(entry), Some(mtime)) if entry.size == metadata.len() && entry.mtime == mtime => Some(entry.md5sum.clone()),
+            _ => None
+        }
+    }
+
+    /// Remembers the hash of `path`, as of `metadata`.
+    pub fn insert(&mut self, path: &Path, metadata: &fs::Metadata, md5sum: Vec<u8>) {
+        if let Some(mtime) = mtime(metadata) {
+            self.entries.insert(path.to_path_buf(), Entry {
+                size: metadata.len(),
+                mtime: mtime,
+                md5sum: md5sum
+            });
+            self.dirty = true;
+        }
+    }
+
+    /// Forgets the cached hash of `path`, if there is one
+    pub fn remove(&mut self, path: &Path) {
+        if self.entries.remove(path).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    /// Writes the cache back out, if anything's changed since it was loaded.
+    pub fn save(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let entries = &self.entries;
+        let (temp, _) = try!(update::write_temp_file_with(&self.path, |f| {
+            let mut out = BufWriter::new(f);
+            for (path, entry) in entries {
+                try!(writeln!(out, "{},{},{},{},{}", entry.size, entry.mtime.0, entry.mtime.1,
+                              entry.md5sum.to_hex().to_uppercase(), path.to_string_lossy()));
+            }
+            out.flush()
+        }));
+        if let Err(e) = fs::rename(&temp, &self.path) {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testutil::{scratch_dir, write};
+    use std::fs;
+
+    #[test]
+    pub fn test_hash_cache() {
+        let dir = scratch_dir("hashcache");
+        let file = dir.join("Warframe, the game.exe");
+        write(&file, b"hello");
+        let metadata = fs::metadata(&file).unwrap();
+
+        let mut cache = HashCache::load_from(dir.join("hashes.txt"));
+        assert_eq!(cache.get(&file, &metadata), None);
+        cache.insert(&file, &metadata, vec![1; 16]);
+        cache.save().unwrap();
+
+        let mut cache = HashCache::load_from(dir.join("hashes.txt"));
+        assert_eq!(cache.get(&file, &metadata), Some(vec![1; 16]));
+
+        // Any change to the file makes the cached hash stale
+        fs::OpenOptions::new().append(true).open(&file).unwrap().write_all(b" world").unwrap();
+        assert_eq!(cache.get(&file, &fs::metadata(&file).unwrap()), None);
+
+        // Forgetting one file leaves the others alone
+        let other = dir.join("Launcher.exe");
+        write(&other, b"launcher");
+        let other_metadata = fs::metadata(&other).unwrap();
+        cache.insert(&other, &other_metadata, vec![2; 16]);
+        cache.remove(&file);
+        assert_eq!(cache.get(&file, &metadata), None);
+        assert_eq!(cache.get(&other, &other_metadata), Some(vec![2; 16]));
+
+        cache.save().unwrap();
+
+        let cache = HashCache::load_from(dir.join("hashes.txt"));
+        assert_eq!(cache.get(&file, &metadata), None);
+        assert_eq!(cache.get(&other, &other_metadata), Some(vec![2; 16]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
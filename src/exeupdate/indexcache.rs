@@ -0,0 +1,202 @@
+//! Stage 1 Index Cache
+//!
+//! The last index downloaded is kept at `$XDG_CACHE_HOME/wfupdate/index/index.txt`, exactly as it came off the server,
+//! next to an `index.txt.meta` holding when it was fetched (as a unix timestamp) and which content source it came from.
+//!
+//! `get_index()` reuses the cached copy while it's younger than the `update:indexttl` config value,
+//! and can be told to use it no matter how old it is, for checking an install without a network connection.
+//! `peek_index()` does the same without caching anything, for looking without changing anything.
+//!
+//! When a newly downloaded index differs from the cached one, the old one is kept as `index.previous.txt`,
+//! so there's always something to diff against.
+
+use super::super::config;
+use super::downloader::{self, DownloaderError};
+use super::source::ContentSource;
+use std::io;
+use std::io::prelude::*;
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use time;
+
+/// How long a cached index is used for by default, in seconds.
+pub const DEFAULT_TTL: i64 = 600;
+
+/// A cached copy of the index
+#[derive(Debug, Clone)]
+pub struct CachedIndex {
+    /// The (decompressed) index
+    pub index: String,
+    /// When it was downloaded, as a unix timestamp
+    pub fetched: i64,
+    /// The `describe()` of the content source it came from
+    pub source: String
+}
+
+impl CachedIndex {
+    /// How many seconds ago the index was downloaded
+    pub fn age(&self) -> i64 {
+        time::now().to_timespec().sec - self.fetched
+    }
+}
+
+static CURRENT: &'static str = "index.txt";
+static PREVIOUS: &'static str = "index.previous.txt";
+
+fn cache_dir() -> PathBuf {
+    config::cache_dir("index")
+}
+
+/// Where the cached index is kept
+pub fn index_path() -> PathBuf {
+    cache_dir().join(CURRENT)
+}
+
+/// Where the index before the current cached one is kept
+pub fn previous_index_path() -> PathBuf {
+    cache_dir().join(PREVIOUS)
+}
+
+fn meta_path(index: &PathBuf) -> PathBuf {
+    index.with_extension("txt.meta")
+}
+
+/// Loads the cached index, if there is one.
+pub fn load() -> Option<CachedIndex> {
+    load_from(index_path())
+}
+
+/// Loads the index that was cached before the current one, if there is one.
+pub fn load_previous() -> Option<CachedIndex> {
+    load_from(previous_index_path())
+}
+
+fn load_from(path: PathBuf) -> Option<CachedIndex> {
+    let mut index = String::new();
+    let mut meta = String::new();
+    if File::open(&path).and_then(|mut f| f.read_to_string(&mut index)).is_err() ||
+       File::open(meta_path(&path)).and_then(|mut f| f.read_to_string(&mut meta)).is_err() {
+        return None;
+    }
+    let mut lines = meta.lines();
+    let fetched = match lines.next().and_then(|l| l.trim().parse().ok()) {
+        Some(t) => t,
+        None => return None
+    };
+    Some(CachedIndex {
+        index: index,
+        fetched: fetched,
+        source: lines.next().unwrap_or("").to_string()
+    })
+}
+
+/// Caches a freshly downloaded index, moving the current one to `index.previous.txt` if it's different.
+pub fn store(index: &str, source: &ContentSource) -> io::Result<()> {
+    store_in(&cache_dir(), index, source)
+}
+
+fn store_in(dir: &Path, index: &str, source: &ContentSource) -> io::Result<()> {
+    let (current, previous) = (dir.join(CURRENT), dir.join(PREVIOUS));
+    if let Some(cached) = load_from(current.clone()) {
+        if cached.index != index {
+            try!(fs::rename(&current, &previous));
+            try!(fs::rename(meta_path(&current), meta_path(&previous)));
+        }
+    }
+    let mut f = try!(File::create(&current));
+    try!(f.write_all(index.as_bytes()));
+    let mut meta = try!(File::create(meta_path(&current)));
+    write!(meta, "{}\n{}\n", time::now().to_timespec().sec, source.describe())
+}
+
+/// Marks the cached index as stale, so the next `get_index()` downloads it again.
+///
+/// The index itself is kept, for `--offline` and for diffing against.
+pub fn expire() -> io::Result<()> {
+    match load() {
+        Some(cached) => {
+            let mut meta = try!(File::create(meta_path(&index_path())));
+            write!(meta, "0\n{}\n", cached.source)
+        },
+        None => Ok(())
+    }
+}
+
+/// Gets the index, either from the cache or from `source`.
+///
+/// The cached index is used if it came from the same source and is less than `ttl` seconds old,
+/// or whatever its age if `offline` is set (in which case `source` isn't touched at all).
+/// Anything downloaded is cached for next time.
+///
+/// Returns the index, along with the cache entry it came from if it wasn't downloaded.
+pub fn get_index(source: &ContentSource, ttl: i64, offline: bool) -> Result<(String, Option<CachedIndex>), DownloaderError> {
+    fetch(&cache_dir(), source, ttl, offline, true)
+}
+
+/// Like `get_index()`, but a downloaded index isn't cached, so the cache is left exactly as it was.
+pub fn peek_index(source: &ContentSource, ttl: i64, offline: bool) -> Result<(String, Option<CachedIndex>), DownloaderError> {
+    fetch(&cache_dir(), source, ttl, offline, false)
+}
+
+fn fetch(dir: &Path, source: &ContentSource, ttl: i64, offline: bool, keep: bool) -> Result<(String, Option<CachedIndex>), DownloaderError> {
+    if let Some(cached) = load_from(dir.join(CURRENT)) {
+        if offline || (cached.source == source.describe() && cached.age() < ttl) {
+            return Ok((cached.index.clone(), Some(cached)));
+        }
+    }
+    if offline {
+        return Err(DownloaderError::IOError(io::Error::new(io::ErrorKind::NotFound, "no cached file list")));
+    }
+    let index = try!(downloader::get_index(source));
+    if keep {
+        // Failing to cache it isn't worth failing the update over.
+        let _ = store_in(dir, index.as_str(), source);
+    }
+    Ok((index, None))
+}
+
+/// The `update:indexttl` config value, or `DEFAULT_TTL`
+pub fn ttl_from_config(config: &::ini::Ini) -> i64 {
+    config::parse_usize(config.get_from(Some("update"), "indexttl"), DEFAULT_TTL as usize) as i64
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::source::DirectorySource;
+    use super::super::testutil::{scratch_dir, write, read, test_content, TEST_LZMA};
+    use std::fs;
+
+    #[test]
+    pub fn test_peek_leaves_cache_alone() {
+        let dir = scratch_dir("indexcache");
+        let mirror = dir.join("mirror");
+        write(&mirror.join("index.txt.lzma"), TEST_LZMA);
+        let source = DirectorySource::new(mirror.clone());
+        let index = String::from_utf8(test_content()).unwrap();
+
+        // Nothing cached, and nothing cached afterwards either
+        let cache = dir.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        assert_eq!(fetch(&cache, &source, DEFAULT_TTL, false, false).unwrap().0, index);
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 0);
+
+        // A stale cached index gets downloaded again, but not replaced
+        write(&cache.join(CURRENT), b"old index");
+        write(&meta_path(&cache.join(CURRENT)), format!("0\n{}\n", source.describe()).as_bytes());
+        let (peeked, cached) = fetch(&cache, &source, DEFAULT_TTL, false, false).unwrap();
+        assert_eq!(peeked, index);
+        assert!(cached.is_none());
+        assert_eq!(read(&cache.join(CURRENT)), b"old index");
+        assert_eq!(read(&meta_path(&cache.join(CURRENT))), format!("0\n{}\n", source.describe()).into_bytes());
+        assert!(!cache.join(PREVIOUS).exists());
+
+        // Whereas getting it for real caches it
+        fetch(&cache, &source, DEFAULT_TTL, false, true).unwrap();
+        assert_eq!(read(&cache.join(CURRENT)), index.into_bytes());
+        assert_eq!(read(&cache.join(PREVIOUS)), b"old index");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
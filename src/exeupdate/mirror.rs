@@ -0,0 +1,191 @@
+//! Local Origin Mirror
+//!
+//! `wfupdate mirror sync` copies the index and the stage 1 `.lzma` files into a directory laid out like origin.warframe.com.
+//! `MirrorHandler` serves such a directory over plain HTTP, so other wfupdate instances on the network can set
+//! `update:source` to `http://<host>:<port>/` and download from it instead of from DE.
+//!
+//! Requests for the cache-busting `/origin/XXXXXXXX/index.txt.lzma` path are answered with `/index.txt.lzma`,
+//! and `Range` requests are supported so that interrupted downloads can resume.
+//!
+//! A mirror only has the file types it was synced with, but it serves the full index. Files it doesn't have get a 404,
+//! so clients should list another source after it (ex `http://<host>:<port>/;http://origin.warframe.com/`)
+//! and `HttpSource` will get those from there.
+
+use hyper::server::{Handler, Request, Response};
+use hyper::header::{AcceptRanges, ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec, Range, RangeUnit};
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::fs::File;
+use std::path::PathBuf;
+
+/// Maps a request path onto a file in the mirror directory `root`.
+///
+/// Returns `None` for anything that would escape `root` (`..`, empty components and the like).
+pub fn resolve_path(root: &PathBuf, request_path: &str) -> Option<PathBuf> {
+    let path = match request_path.find('?') {
+        Some(pos) => request_path.split_at(pos).0,
+        None => request_path
+    };
+    let parts: Vec<&str> = path.trim_left_matches('/').split('/').collect();
+    if parts.len() == 3 && parts[0] == "origin" && parts[2] == "index.txt.lzma" {
+        return Some(root.join("index.txt.lzma"));
+    }
+    let mut out = root.clone();
+    for part in parts {
+        if part.len() == 0 || part == "." || part == ".." || part.contains('\\') {
+            return None;
+        }
+        out.push(part);
+    }
+    Some(out)
+}
+
+/// A hyper `Handler` that serves files out of a mirror directory.
+pub struct MirrorHandler {
+    /// The mirror directory
+    pub root: PathBuf,
+    /// Print a line for each request
+    pub verbose: bool
+}
+
+impl MirrorHandler {
+    fn serve_file(&self, req: &Request, mut res: Response, path: PathBuf) -> io::Result<()> {
+        let mut f = match File::open(&path) {
+            Ok(f) => f,
+            Err(_) => {
+                *res.status_mut() = StatusCode::NotFound;
+                return res.send(b"Not Found");
+            }
+        };
+        let len = try!(f.metadata()).len();
+        let (start, end) = match req.headers.get::<Range>() {
+            Some(&Range::Bytes(ref specs)) if specs.len() == 1 => match specs[0] {
+                ByteRangeSpec::FromTo(from, to) => (from, to.saturating_add(1)),
+                ByteRangeSpec::AllFrom(from) => (from, len),
+                ByteRangeSpec::Last(n) => (len.saturating_sub(n), len)
+            },
+            _ => (0, len)
+        };
+        let end = if end > len { len } else { end };
+        res.headers_mut().set(AcceptRanges(vec![RangeUnit::Bytes]));
+        if start > 0 || end < len {
+            if start >= end {
+                *res.status_mut() = StatusCode::RangeNotSatisfiable;
+                res.headers_mut().set(ContentRange(ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: Some(len)
+                }));
+                return res.send(b"");
+            }
+            *res.status_mut() = StatusCode::PartialContent;
+            res.headers_mut().set(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((start, end - 1)),
+                instance_length: Some(len)
+            }));
+        }
+        res.headers_mut().set(ContentLength(end - start));
+        if req.method == Method::Head {
+            return res.start().and_then(|res| res.end());
+        }
+        try!(f.seek(SeekFrom::Start(start)));
+        let mut res = try!(res.start());
+        try!(io::copy(&mut f.take(end - start), &mut res));
+        res.end()
+    }
+}
+
+impl Handler for MirrorHandler {
+    fn handle(&self, req: Request, mut res: Response) {
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.clone(),
+            _ => String::new()
+        };
+        if self.verbose {
+            println!("{} {} {}", req.remote_addr, req.method, path);
+        }
+        if req.method != Method::Get && req.method != Method::Head {
+            *res.status_mut() = StatusCode::MethodNotAllowed;
+            let _ = res.send(b"Method Not Allowed");
+            return;
+        }
+        match resolve_path(&self.root, path.as_str()) {
+            Some(file) => {
+                if let Err(err) = self.serve_file(&req, res, file) {
+                    if self.verbose {
+                        println!("Error serving {}: {}", path, err);
+                    }
+                }
+            },
+            None => {
+                *res.status_mut() = StatusCode::BadRequest;
+                let _ = res.send(b"Bad Request");
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::source::{ContentSource, HttpSource};
+    use super::super::network::NetworkConfig;
+    use super::super::testutil::{scratch_dir, write};
+    use hyper::server::{Server, Request, Response};
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn test_resolve_path() {
+        let root = PathBuf::from("/srv/mirror");
+        assert_eq!(resolve_path(&root, "/Tools/Launcher.exe.F336FD22FDF21024C75FF46FE8F7A06E.lzma"),
+                   Some(PathBuf::from("/srv/mirror/Tools/Launcher.exe.F336FD22FDF21024C75FF46FE8F7A06E.lzma")));
+        assert_eq!(resolve_path(&root, "/origin/1A2B3C4D/index.txt.lzma"), Some(PathBuf::from("/srv/mirror/index.txt.lzma")));
+        assert_eq!(resolve_path(&root, "/index.txt.lzma?foo=bar"), Some(PathBuf::from("/srv/mirror/index.txt.lzma")));
+        assert_eq!(resolve_path(&root, "/../../etc/passwd"), None);
+        assert_eq!(resolve_path(&root, "/Tools//Launcher.exe"), None);
+        assert_eq!(resolve_path(&root, "/"), None);
+    }
+
+    #[test]
+    pub fn test_serve_mirror() {
+        let root = scratch_dir("mirror");
+        write(&root.join("Tools/Launcher.exe.lzma"), b"launcher");
+        write(&root.join("index.txt.lzma"), b"index");
+
+        let mut listening = Server::http("127.0.0.1:0").unwrap().handle(MirrorHandler {
+            root: root.clone(),
+            verbose: false
+        }).unwrap();
+        let source = HttpSource::new(format!("http://{}/", listening.socket).as_str(), 1);
+        let read = |path: &str, offset: u64| {
+            let mut out = vec![];
+            source.open(path, offset).unwrap().read_to_end(&mut out).unwrap();
+            out
+        };
+        assert_eq!(read("/Tools/Launcher.exe.lzma", 0), b"launcher");
+        assert_eq!(read("/Tools/Launcher.exe.lzma", 3), b"ncher");
+        assert_eq!(read("/Tools/Launcher.exe.lzma", 8), b"");
+        assert_eq!(read(source.index_path().as_str(), 0), b"index");
+        assert!(source.open("/Warframe.exe.lzma", 0).is_err());
+
+        // Files the mirror doesn't have come from the next source instead
+        let mut origin = Server::http("127.0.0.1:0").unwrap().handle(|_: Request, res: Response| {
+            res.send(b"warframe").unwrap();
+        }).unwrap();
+        let source = HttpSource::with_config(vec![format!("http://{}/", listening.socket).as_str(),
+                                                  format!("http://{}/", origin.socket).as_str()],
+                                             1, NetworkConfig::default()).unwrap();
+        let mut out = vec![];
+        source.open("/Warframe.exe.lzma", 0).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"warframe");
+
+        origin.close().unwrap();
+        listening.close().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -9,6 +9,22 @@ pub mod parser;
 pub mod downloader;
 pub mod checker;
 pub mod update;
+pub mod snapshot;
+pub mod plan;
+pub mod source;
+pub mod mirror;
+pub mod indexcache;
+pub mod diff;
+pub mod stats;
+pub mod hashcache;
+pub mod orphans;
+pub mod pins;
+pub mod validate;
+pub mod network;
+pub mod ratelimit;
+pub mod contentcache;
+#[cfg(test)]
+mod testutil;
 pub use self::parser::FileType;
 
 
@@ -0,0 +1,144 @@
+//! Orphaned File Detection
+//!
+//! Finds files in the install that the index doesn't list, like DLLs and CEF files from old patches,
+//! or a `Warframe.x64.exe` that's no longer used.
+//!
+//! Some files are supposed to be there without being in the index (logs, settings, the stage 2 cache),
+//! those are matched against `ALLOWLIST` and never reported. Neither are files pinned with `update:pin`.
+
+use super::super::paths;
+use super::pins::Pins;
+use super::validate;
+use std::collections::HashSet;
+use std::io;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Files that belong in the install even though the index doesn't list them.
+///
+/// `Name/` matches a top-level directory and everything in it, `*.ext` matches a file extension anywhere,
+/// and anything else matches a file name anywhere. Matching ignores case, like Windows does.
+pub static ALLOWLIST: &'static [&'static str] = &[
+    "Cache.Windows/",
+    "EE.cfg",
+    "*.log"
+];
+
+/// Whether `disk_path` (an index-style path, ex `/Cache.Windows/B.Font.cache`) is covered by `ALLOWLIST`.
+pub fn allowed(disk_path: &str) -> bool {
+    let disk_path = disk_path.to_lowercase();
+    let name = match disk_path.trim_right_matches('/').rfind('/') {
+        Some(pos) => disk_path.split_at(pos + 1).1.trim_right_matches('/'),
+        None => disk_path.as_str()
+    };
+    ALLOWLIST.iter().any(|pattern| {
+        let pattern = pattern.to_lowercase();
+        if pattern.ends_with('/') {
+            disk_path.starts_with(format!("/{}", pattern).as_str())
+        } else if pattern.starts_with("*.") {
+            name.ends_with(pattern.split_at(1).1)
+        } else {
+            name == pattern
+        }
+    })
+}
+
+/// Finds every file under `roots` that isn't in `files`, `ALLOWLIST` or `pins`.
+///
+/// `files` has to be the whole index, anything left out of it (ex lines skipped by a lenient parse) will look like an orphan.
+/// Index paths are normalized the same way an update writes them (see `validate::normalize_path()`),
+/// and entries that fail validation are ignored, since an update wouldn't write them either.
+/// Each root is paired with whether it's the Launcher's `Downloaded/Public` directory (see `paths::is_launcher_path()`),
+/// so a file only counts as known if the index would actually put it in that root.
+/// Symlinks are left alone. The result is sorted.
+pub fn find_orphans(files: &[super::File], roots: &[(PathBuf, bool)], pins: &Pins) -> io::Result<Vec<PathBuf>> {
+    let mut known: [HashSet<String>; 2] = [HashSet::new(), HashSet::new()];
+    for file in files {
+        let disk_path = match validate::normalize_path(file.disk_path.as_str()) {
+            Ok(disk_path) => disk_path,
+            Err(_) => continue
+        };
+        let launcher = paths::is_launcher_path(disk_path.as_str());
+        known[launcher as usize].insert(disk_path.to_lowercase());
+    }
+    let mut orphans = vec![];
+    for &(ref root, launcher) in roots {
+        try!(walk(root, "", &known[launcher as usize], pins, &mut orphans));
+    }
+    orphans.sort();
+    Ok(orphans)
+}
+
+fn walk(dir: &Path, disk_dir: &str, known: &HashSet<String>, pins: &Pins, orphans: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        let disk_path = format!("{}/{}", disk_dir, entry.file_name().to_string_lossy());
+        let metadata = try!(fs::symlink_metadata(entry.path()));
+        if metadata.is_dir() {
+            if !allowed(format!("{}/", disk_path).as_str()) {
+                try!(walk(&entry.path(), disk_path.as_str(), known, pins, orphans));
+            }
+        } else if metadata.is_file() {
+            if !known.contains(&disk_path.to_lowercase()) && !allowed(disk_path.as_str()) && !pins.is_pinned(disk_path.as_str()) {
+                orphans.push(entry.path());
+            }
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::pins::Pins;
+    use super::super::testutil::{scratch_dir, write, file_list, test_file, SAMPLE_INDEX};
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn touch(path: PathBuf) {
+        write(&path, b"");
+    }
+
+    #[test]
+    pub fn test_allowed() {
+        assert!(allowed("/Cache.Windows/B.Font.cache"));
+        assert!(allowed("/cache.windows/"));
+        assert!(allowed("/EE.cfg"));
+        assert!(allowed("/Tools/Debug.LOG"));
+        assert!(!allowed("/Tools/Cache.Windows/B.Font.cache"));
+        assert!(!allowed("/Warframe.x64.exe"));
+    }
+
+    #[test]
+    pub fn test_find_orphans() {
+        let dir = scratch_dir("orphans");
+        let game = dir.join("game");
+        let launcher = dir.join("launcher");
+        touch(game.join("Warframe.exe"));
+        touch(game.join("Warframe.x64.exe"));
+        touch(game.join("EE.cfg"));
+        touch(game.join("Cache.Windows/B.Font.cache"));
+        touch(game.join("Tools/Windows/x86/symsrv.dll"));
+        touch(game.join("Tools/Windows/x86/old.dll"));
+        // Not in the index, but the user's own, pinned
+        touch(game.join("Tools/Windows/x86/d3dcompiler_47.dll"));
+        touch(launcher.join("Tools/Launcher.exe"));
+        // A launcher file in the game dir isn't where the index says it goes
+        touch(game.join("Tools/Launcher.exe"));
+
+        let mut files = file_list(SAMPLE_INDEX);
+        // Odd-looking paths still point at the same file
+        files.push(test_file("//Tools/./Windows/x86//old.dll", b"old"));
+        // and bad ones don't point at anything
+        files.push(test_file("/Tools/../Warframe.x64.exe", b"x64"));
+        let pins = Pins::parse("/Tools/Windows/x86/d3d*.dll");
+        let orphans = find_orphans(&files, &[(game.clone(), false), (launcher.clone(), true)], &pins).unwrap();
+        assert_eq!(orphans, vec![
+            game.join("Tools/Launcher.exe"),
+            game.join("Warframe.x64.exe")
+        ]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
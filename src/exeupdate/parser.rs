@@ -8,6 +8,49 @@
 
 use hex::FromHex;
 use super::File;
+use super::pins::glob_match;
+use std::fmt;
+
+/// What was wrong with a line of the index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// There's no `,` between the file name and the size
+    MissingComma,
+    /// The MD5 hash in the file name is missing or isn't 32 hex digits
+    BadHash,
+    /// The size after the `,` isn't a number
+    BadSize,
+    /// The file name doesn't end in `.lzma`
+    UnexpectedSuffix
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            ParseErrorKind::MissingComma => "missing comma",
+            ParseErrorKind::BadHash => "bad hash",
+            ParseErrorKind::BadSize => "bad size",
+            ParseErrorKind::UnexpectedSuffix => "unexpected suffix, expected .lzma"
+        })
+    }
+}
+
+/// A line of the index that couldn't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Line number, starting from 1
+    pub line: usize,
+    /// The line itself
+    pub text: String,
+    /// What was wrong with it
+    pub kind: ParseErrorKind
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}: {:?}", self.line, self.kind, self.text)
+    }
+}
 
 /// Parses a Warframe file index list into a vector of `File`s.
 ///
@@ -21,6 +64,9 @@ use super::File;
 /// /Warframe.exe.3BB594902B2E8037901ED9B2419E8FD5.lzma,6998205
 /// ```
 ///
+/// Blank lines are skipped. Anything else that doesn't look like the above is an error,
+/// see `parse_file_list_lenient()` for skipping those lines instead.
+///
 /// ## Example
 /// ```rust
 /// # use super::File;
@@ -32,48 +78,100 @@ use super::File;
 ///     size: 313612
 /// });
 /// ```
-pub fn parse_file_list(list: String) -> Result<Vec<File>, ()> {
+pub fn parse_file_list(list: String) -> Result<Vec<File>, ParseError> {
     let mut out = vec![];
-    for line in list.lines() {
-        let pos = match line.rfind(',') {
-            Some(p) => p,
-            None => return Err(())
-        };
-        let (filename, size) = line.split_at(pos);
-        let size = size.split_at(1).1; //simple trick to remove the first character of the string (the comma)
-        //md5 hash is 32 chars long, plus 5 for ".lzma", equals 37
-        let (disk_path, raw_md5) = filename.split_at(filename.len()-37);
-        let disk_path = disk_path.split_at(disk_path.len()-1).0;
-        let raw_md5: &str = raw_md5.split_at(32).0; //remove the .lzma at the end
-        let md5: Vec<u8> = match Vec::from_hex(raw_md5.as_bytes()) {
-            Ok(k) => k,
-            Err(_) => return Err(())
-        };
-
-        out.push(File {
-            download_path: filename.to_string(),
-            disk_path: disk_path.to_string(),
-            md5sum: md5,
-            size: match size.parse() {
-                Ok(s) => s,
-                Err(_) => return Err(())
-            }
-        });
+    for (number, line) in list.lines().enumerate() {
+        if line.trim().len() == 0 {
+            continue;
+        }
+        match parse_line(line) {
+            Ok(file) => out.push(file),
+            Err(kind) => return Err(ParseError {
+                line: number + 1,
+                text: line.to_string(),
+                kind: kind
+            })
+        }
     }
     Ok(out)
 }
 
+/// Like `parse_file_list()`, but lines that can't be parsed are skipped instead of failing the whole list.
+///
+/// Returns the files from every good line, and an error for every bad one.
+pub fn parse_file_list_lenient(list: String) -> (Vec<File>, Vec<ParseError>) {
+    let mut out = vec![];
+    let mut errors = vec![];
+    for (number, line) in list.lines().enumerate() {
+        if line.trim().len() == 0 {
+            continue;
+        }
+        match parse_line(line) {
+            Ok(file) => out.push(file),
+            Err(kind) => errors.push(ParseError {
+                line: number + 1,
+                text: line.to_string(),
+                kind: kind
+            })
+        }
+    }
+    (out, errors)
+}
+
+/// Parses a single line of the index, ex `/Warframe.exe.3BB594902B2E8037901ED9B2419E8FD5.lzma,6998205`
+pub fn parse_line(line: &str) -> Result<File, ParseErrorKind> {
+    let pos = match line.rfind(',') {
+        Some(p) => p,
+        None => return Err(ParseErrorKind::MissingComma)
+    };
+    let (filename, size) = line.split_at(pos);
+    let size = size.split_at(1).1; //simple trick to remove the first character of the string (the comma)
+    let size = match size.trim().parse() {
+        Ok(s) => s,
+        Err(_) => return Err(ParseErrorKind::BadSize)
+    };
+    if !filename.ends_with(".lzma") {
+        return Err(ParseErrorKind::UnexpectedSuffix);
+    }
+    let stem = filename.split_at(filename.len() - 5).0;
+    //md5 hash is 32 chars long, with a "." in front of it
+    if stem.len() < 33 || !stem.is_char_boundary(stem.len() - 33) {
+        return Err(ParseErrorKind::BadHash);
+    }
+    let (disk_path, raw_md5) = stem.split_at(stem.len() - 33);
+    if !raw_md5.starts_with('.') {
+        return Err(ParseErrorKind::BadHash);
+    }
+    let md5: Vec<u8> = match Vec::from_hex(raw_md5.split_at(1).1.as_bytes()) {
+        Ok(k) => k,
+        Err(_) => return Err(ParseErrorKind::BadHash)
+    };
+
+    Ok(File {
+        download_path: filename.to_string(),
+        disk_path: disk_path.to_string(),
+        md5sum: md5,
+        size: size
+    })
+}
+
 /// A categorization of a Warframe asset.
 ///
 /// Used to decide if it should be checked and possibly updated or not.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum FileType {
     /// 32-Bit Executable. This should always be present.
-    ///
-    /// Also includes things like language files and driver redistributables.
     Exe32Bit,
     /// 64-Bit Executable. Only needed if you're running the game in 64-bit mode.
     Exe64Bit,
+    /// DirectX-specific files, ex `d3dcompiler_47.dll`
+    DirectX,
+    /// Driver installers, anything under `/Drivers`
+    Driver,
+    /// Microsoft runtime redistributables, ex `msvcr110.dll`
+    Redistributable,
+    /// Translations and other language-specific files
+    Language,
     /// Very large game asset that isn't managed by the launcher.
     ///
     /// I don't know why these are in the list.
@@ -86,37 +184,138 @@ pub enum FileType {
     ///
     /// The game will silently not care if you don't have these present.
     SteamAsset,
-    /// An unknown file.
+    /// An unknown file, updated by default.
     ///
     /// As of 2016-10-13, there are no items in the list that aren't categorized.
     Unknown
 }
 
+impl FileType {
+    /// Every category, in the order they're listed to users
+    pub fn all() -> Vec<FileType> {
+        use self::FileType::*;
+        vec![Exe32Bit, Exe64Bit, DirectX, Driver, Redistributable, Language, GameAsset, LauncherAsset, SteamAsset, Unknown]
+    }
+
+    /// A short name for the category, used on the command line and in the config
+    pub fn name(&self) -> &'static str {
+        use self::FileType::*;
+        match *self {
+            Exe32Bit => "exe32",
+            Exe64Bit => "exe64",
+            DirectX => "directx",
+            Driver => "drivers",
+            Redistributable => "redist",
+            Language => "language",
+            GameAsset => "game",
+            LauncherAsset => "launcher",
+            SteamAsset => "steam",
+            Unknown => "unknown"
+        }
+    }
+
+    /// The opposite of `name()`
+    pub fn from_name(name: &str) -> Option<FileType> {
+        FileType::all().into_iter().find(|t| t.name() == name)
+    }
+}
+
+
+/// The built-in categorization rules, in the order they're tried.
+///
+/// Patterns are the same globs as `update:pin` (see `pins`).
+pub static DEFAULT_RULES: &'static [(&'static str, FileType)] = &[
+    ("/Tools/CEF3_1**", FileType::LauncherAsset),
+    ("/Tools/Launcher.exe", FileType::LauncherAsset),
+    ("**steam_api**", FileType::SteamAsset),
+    ("**vdf**", FileType::SteamAsset),
+    ("**x64**", FileType::Exe64Bit),
+    ("/Cache.Windows/**", FileType::GameAsset),
+    ("/Lotus/**", FileType::GameAsset),
+    ("**/DirectX/**", FileType::DirectX),
+    ("**/d3d*", FileType::DirectX),
+    ("/Drivers/**", FileType::Driver),
+    ("**redist**", FileType::Redistributable),
+    ("**/msvc*", FileType::Redistributable),
+    ("**/vcruntime*", FileType::Redistributable),
+    ("**/Language*/**", FileType::Language),
+    ("**x86**", FileType::Exe32Bit),
+    ("/Warframe.exe", FileType::Exe32Bit),
+    ("/Tools/**", FileType::Exe32Bit)
+];
+
+/// A table of path patterns and the category files matching them go in.
+///
+/// The first rule that matches wins, and files that don't match any rule are `FileType::Unknown`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rules {
+    rules: Vec<(String, FileType)>
+}
+
+impl Default for Rules {
+    /// Just the built-in `DEFAULT_RULES`
+    fn default() -> Rules {
+        Rules::new(DEFAULT_RULES.iter().map(|&(pattern, filetype)| (pattern.to_string(), filetype)).collect())
+    }
+}
+
+impl Rules {
+    /// Makes a rule table, from (pattern, category) pairs
+    pub fn new(rules: Vec<(String, FileType)>) -> Rules {
+        Rules {
+            rules: rules.into_iter().map(|(pattern, filetype)| {
+                let pattern = pattern.trim();
+                let pattern = if pattern.starts_with('/') { pattern.to_string() } else { format!("/{}", pattern) };
+                (pattern.to_lowercase(), filetype)
+            }).collect()
+        }
+    }
+
+    /// Parses a `;`-separated list of `pattern:category` rules, ex `/Tools/Windows/x86/symsrv.dll:unknown`,
+    /// and puts them in front of the built-in ones.
+    ///
+    /// Categories are the `FileType::name()`s.
+    pub fn parse(list: &str) -> Result<Rules, String> {
+        let mut rules = vec![];
+        for rule in list.split(';').map(|r| r.trim()).filter(|r| r.len() > 0) {
+            let pos = match rule.rfind(':') {
+                Some(pos) => pos,
+                None => return Err(format!("\"{}\" should look like pattern:category", rule))
+            };
+            let (pattern, name) = rule.split_at(pos);
+            match FileType::from_name(name.split_at(1).1.trim()) {
+                Some(filetype) => rules.push((pattern.to_string(), filetype)),
+                None => return Err(format!("unknown category in \"{}\"", rule))
+            }
+        }
+        rules.extend(DEFAULT_RULES.iter().map(|&(pattern, filetype)| (pattern.to_string(), filetype)));
+        Ok(Rules::new(rules))
+    }
+
+    /// The built-in rules, with any from the `update:rules` config value in front of them
+    pub fn from_config(config: &::ini::Ini) -> Result<Rules, String> {
+        Rules::parse(config.get_from(Some("update"), "rules").unwrap_or(""))
+    }
 
-/// Categorizes a Warframe file asset
+    /// Categorizes a Warframe file asset, see `categorize()`.
+    pub fn categorize(&self, item: &File) -> FileType {
+        let name = item.disk_path.to_lowercase();
+        for &(ref pattern, filetype) in &self.rules {
+            if glob_match(pattern.as_bytes(), name.as_bytes()) {
+                return filetype;
+            }
+        }
+        FileType::Unknown
+    }
+}
+
+/// Categorizes a Warframe file asset using the built-in rules (ignoring `update:rules`, see `Rules` for that)
 ///
 /// Paths should be how they are in the file list, ex
 /// "/Warframe.exe" not "Program Files/Warframe/Downloaded/Public/Warframe.exe"
 ///
 pub fn categorize(item: &File) -> FileType {
-    let ref name = item.disk_path;
-    if name.starts_with("/Tools/CEF3_1") || name == "/Tools/Launcher.exe" {
-        FileType::LauncherAsset
-    } else if name.contains("steam_api") || name.contains("vdf") {
-        FileType::SteamAsset
-    } else if name.contains("x64") {
-        FileType::Exe64Bit
-    } else if name.contains("x86") ||
-              name == "/Warframe.exe" ||
-              name.starts_with("/Drivers") ||
-              name.starts_with("/Tools") {
-        FileType::Exe32Bit
-    } else if name.starts_with("/Cache.Windows") ||
-              name.starts_with("/Lotus") {
-        FileType::GameAsset
-    } else {
-        FileType::Unknown
-    }
+    Rules::default().categorize(item)
 }
 
 
@@ -143,6 +342,29 @@ mod tests {
         assert_eq!(quickcat!("/Tools/Windows/steamController.vdf"), SteamAsset);
         assert_eq!(quickcat!("/Tools/CEF3_1/launcher.zip"), LauncherAsset);
         assert_eq!(quickcat!("/etc/passwd"), Unknown);
+        assert_eq!(quickcat!("/Tools/Windows/x86/msvcr110.dll"), Redistributable);
+        assert_eq!(quickcat!("/Tools/Windows/x86/d3dcompiler_47.dll"), DirectX);
+        assert_eq!(quickcat!("/Tools/Windows/x64/d3dcompiler_47.dll"), Exe64Bit);
+        assert_eq!(quickcat!("/Drivers/PhysX/setup.exe"), Driver);
+        assert_eq!(quickcat!("/Tools/Languages/de.txt"), Language);
+        assert_eq!(quickcat!("/Tools/Windows/x86/symsrv.dll"), Exe32Bit);
+    }
+
+    #[test]
+    pub fn test_rules() {
+        let file = |path: &str| super::super::File {
+            disk_path: path.to_string(),
+            download_path: "".to_string(),
+            md5sum: vec![],
+            size: 0
+        };
+        let rules = Rules::parse("Tools/Windows/x86/symsrv.dll:unknown; /etc/**:game").unwrap();
+        assert_eq!(rules.categorize(&file("/Tools/Windows/x86/symsrv.dll")), Unknown);
+        assert_eq!(rules.categorize(&file("/etc/passwd")), GameAsset);
+        // the built-in rules still apply after the user's
+        assert_eq!(rules.categorize(&file("/Warframe.exe")), Exe32Bit);
+        assert!(Rules::parse("/Warframe.exe:nonsense").is_err());
+        assert!(Rules::parse("/Warframe.exe").is_err());
     }
 
     #[test]
@@ -162,10 +384,34 @@ mod tests {
 
         // bad filesize
         let result = parse_file_list("/Warframe.exe.3BB594902B2E8037901ED9B2419E8FD5.lzma,dskfn1j3r\r\n".to_string());
-        assert!(result.is_err());
+        assert_eq!(result.unwrap_err().kind, ParseErrorKind::BadSize);
 
         // invalid MD5 hash
         let result = parse_file_list("/Warframe.exe.CATSCATSCATS8037901ED9B2419E8FD5.lzma,6998205\r\n".to_string());
-        assert!(result.is_err());
+        assert_eq!(result.unwrap_err().kind, ParseErrorKind::BadHash);
+    }
+
+    #[test]
+    pub fn test_parse_errors() {
+        // These used to panic
+        assert_eq!(parse_line("/a.lzma,1"), Err(ParseErrorKind::BadHash));
+        assert_eq!(parse_line("/é00000000000000000000000000000000.lzma,1"), Err(ParseErrorKind::BadHash));
+        assert_eq!(parse_line(""), Err(ParseErrorKind::MissingComma));
+        assert_eq!(parse_line("/Warframe.exe.3BB594902B2E8037901ED9B2419E8FD5ézma,1"), Err(ParseErrorKind::UnexpectedSuffix));
+        assert_eq!(parse_line("/Warframe.exe3BB594902B2E8037901ED9B2419E8FD5.lzma,1"), Err(ParseErrorKind::BadHash));
+        assert_eq!(parse_line("/Warframe.exe.3BB594902B2E8037901ED9B2419E8FD5.lzma 6998205"), Err(ParseErrorKind::MissingComma));
+
+        let list = "/Tools/Launcher.exe.F336FD22FDF21024C75FF46FE8F7A06E.lzma,313612\r\n\
+                    \r\n\
+                    /Warframe.exe.3BB594902B2E8037901ED9B2419E8FD5.zip,6998205\r\n\
+                    /Warframe.x64.exe.81E34ABBF3AEFAD7E56D157EDE08E178.lzma,7500000\r\n";
+        let err = parse_file_list(list.to_string()).unwrap_err();
+        assert_eq!(err.line, 3);
+        assert_eq!(err.kind, ParseErrorKind::UnexpectedSuffix);
+        assert_eq!(err.text, "/Warframe.exe.3BB594902B2E8037901ED9B2419E8FD5.zip,6998205");
+
+        let (files, errors) = parse_file_list_lenient(list.to_string());
+        assert_eq!(files.len(), 2);
+        assert_eq!(errors, vec![err]);
     }
 }
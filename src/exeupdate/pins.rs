@@ -0,0 +1,101 @@
+//! Pinned Files
+//!
+//! Lets the user keep their own version of stage 1 files, ex Wine-friendly builds of `Tools/Windows/x86/*.dll`.
+//! Pinned files are never checked or downloaded, so an update won't put DE's version back.
+//!
+//! Pins come from the `update:pin` config value, a `;`-separated list of index paths or glob patterns.
+//! In patterns `*` matches anything but `/`, `**` matches anything at all, and `?` matches a single character.
+//! Matching ignores case, like Windows does, and the leading `/` is optional.
+
+/// A set of pin patterns
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pins {
+    patterns: Vec<String>
+}
+
+impl Pins {
+    /// Makes a set of pins from a list of patterns
+    pub fn new(patterns: Vec<String>) -> Pins {
+        Pins {
+            patterns: patterns.into_iter()
+                .map(|p| p.trim().to_string())
+                .filter(|p| p.len() > 0)
+                .map(|p| if p.starts_with('/') { p } else { format!("/{}", p) }.to_lowercase())
+                .collect()
+        }
+    }
+
+    /// Parses a `;`-separated list of patterns, like `update:pin`
+    pub fn parse(list: &str) -> Pins {
+        Pins::new(list.split(';').map(String::from).collect())
+    }
+
+    /// The pins from the `update:pin` config value
+    pub fn from_config(config: &::ini::Ini) -> Pins {
+        Pins::parse(config.get_from(Some("update"), "pin").unwrap_or(""))
+    }
+
+    /// True if there aren't any pins
+    pub fn is_empty(&self) -> bool {
+        self.patterns.len() == 0
+    }
+
+    /// Whether the file at `disk_path` (ex `/Tools/Windows/x86/d3dcompiler_47.dll`) is pinned
+    pub fn is_pinned(&self, disk_path: &str) -> bool {
+        let disk_path = disk_path.to_lowercase();
+        self.patterns.iter().any(|pattern| glob_match(pattern.as_bytes(), disk_path.as_bytes()))
+    }
+
+    /// Splits `files` into (not pinned, pinned)
+    pub fn partition(&self, files: Vec<super::File>) -> (Vec<super::File>, Vec<super::File>) {
+        files.into_iter().partition(|file| !self.is_pinned(file.disk_path.as_str()))
+    }
+}
+
+/// Matches `path` against a glob `pattern`, see the module docs for the syntax.
+pub fn glob_match(pattern: &[u8], path: &[u8]) -> bool {
+    if pattern.len() == 0 {
+        return path.len() == 0;
+    }
+    if pattern.starts_with(b"**") {
+        let rest = &pattern[2..];
+        return (0..path.len() + 1).any(|i| glob_match(rest, &path[i..]));
+    }
+    match pattern[0] {
+        b'*' => {
+            let rest = &pattern[1..];
+            // `*` can swallow anything up to the next `/`
+            let limit = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+            (0..limit + 1).any(|i| glob_match(rest, &path[i..]))
+        },
+        b'?' => path.len() > 0 && path[0] != b'/' && glob_match(&pattern[1..], &path[1..]),
+        c => path.len() > 0 && path[0] == c && glob_match(&pattern[1..], &path[1..])
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_glob_match() {
+        assert!(glob_match(b"/Tools/Windows/x86/*.dll", b"/Tools/Windows/x86/d3dcompiler_47.dll"));
+        assert!(!glob_match(b"/Tools/Windows/x86/*.dll", b"/Tools/Windows/x86/sub/d3dcompiler_47.dll"));
+        assert!(!glob_match(b"/Tools/Windows/x86/*.dll", b"/Tools/Windows/x86/symsrv.yes"));
+        assert!(glob_match(b"/Tools/**.dll", b"/Tools/Windows/x86/symsrv.dll"));
+        assert!(glob_match(b"/Warframe.?64.exe", b"/Warframe.x64.exe"));
+        assert!(glob_match(b"/Warframe.exe", b"/Warframe.exe"));
+        assert!(!glob_match(b"/Warframe.exe", b"/Warframe.exe.bak"));
+    }
+
+    #[test]
+    pub fn test_pins() {
+        let pins = Pins::parse("Tools/Windows/x86/*.dll; /Warframe.x64.exe ;");
+        assert!(!pins.is_empty());
+        assert!(pins.is_pinned("/Tools/Windows/x86/D3DCompiler_47.dll"));
+        assert!(pins.is_pinned("/warframe.x64.exe"));
+        assert!(!pins.is_pinned("/Warframe.exe"));
+        assert!(Pins::parse("").is_empty());
+    }
+}
@@ -0,0 +1,196 @@
+//! Persisted Stage 1 Update Plan
+//!
+//! Once a stage 1 update has worked out which files need downloading, that list is saved to
+//! `$XDG_CACHE_HOME/wfupdate/staging/plan.txt`. If the update gets interrupted, the next `wfupdate update`
+//! loads the plan and carries on with the same files (and their partial downloads) instead of starting over.
+//!
+//! The plan uses the same format as the launcher index, so it's read back with `parser::parse_file_list()`.
+//! Next to it, `plan.txt.meta` records which wineprefix and which index (by MD5 hash) the plan was worked out for,
+//! so it's only ever resumed for the same install against the same file list.
+
+use super::parser;
+use super::downloader::{self, staging_dir};
+use hex::ToHex;
+use std::io;
+use std::io::prelude::*;
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// What a plan was worked out for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlanTarget {
+    /// The wineprefix being updated
+    pub wineprefix: PathBuf,
+    /// The MD5 hash of the (decompressed) index the files were checked against, in hex
+    pub index_hash: String
+}
+
+impl PlanTarget {
+    /// The target for updating `wineprefix` to `index`
+    pub fn new(wineprefix: PathBuf, index: &str) -> PlanTarget {
+        PlanTarget {
+            wineprefix: wineprefix,
+            index_hash: downloader::md5sum(index.as_bytes()).to_hex()
+        }
+    }
+}
+
+/// A saved plan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    /// What it was worked out for
+    pub target: PlanTarget,
+    /// The files left to download
+    pub files: Vec<super::File>
+}
+
+/// Why a saved plan can't be carried on with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stale {
+    /// It was for another wineprefix, this one
+    OtherWineprefix(PathBuf),
+    /// The index has changed since it was worked out
+    IndexChanged
+}
+
+fn plan_path(dir: &Path) -> PathBuf {
+    dir.join("plan.txt")
+}
+
+fn meta_path(dir: &Path) -> PathBuf {
+    dir.join("plan.txt.meta")
+}
+
+/// Saves the list of files a stage 1 update of `target` is about to download
+pub fn save(files: &[super::File], target: &PlanTarget) -> io::Result<()> {
+    save_in(&staging_dir(), files, target)
+}
+
+fn save_in(dir: &Path, files: &[super::File], target: &PlanTarget) -> io::Result<()> {
+    let mut f = try!(File::create(plan_path(dir)));
+    for file in files {
+        try!(write!(f, "{},{}\r\n", file.download_path, file.size));
+    }
+    try!(f.sync_all());
+    let mut meta = try!(File::create(meta_path(dir)));
+    try!(write!(meta, "{}\n{}\n", target.wineprefix.display(), target.index_hash));
+    meta.sync_all()
+}
+
+/// Loads the plan left behind by an interrupted update of `target`, if there is one.
+///
+/// A plan for a different wineprefix or index is an error, and left for the caller to `clear()`.
+/// Plans that can't be read, or without a `plan.txt.meta` (ex from an older wfupdate), are treated as not being there,
+/// since there's no telling what they're for.
+pub fn load_for(target: &PlanTarget) -> Result<Option<Plan>, Stale> {
+    load_from(&staging_dir(), target)
+}
+
+fn load_from(dir: &Path, target: &PlanTarget) -> Result<Option<Plan>, Stale> {
+    let mut list = String::new();
+    let mut meta = String::new();
+    if File::open(plan_path(dir)).and_then(|mut f| f.read_to_string(&mut list)).is_err() ||
+       File::open(meta_path(dir)).and_then(|mut f| f.read_to_string(&mut meta)).is_err() {
+        return Ok(None);
+    }
+    let mut lines = meta.lines();
+    let saved = match (lines.next(), lines.next()) {
+        (Some(wineprefix), Some(index_hash)) => PlanTarget {
+            wineprefix: PathBuf::from(wineprefix),
+            index_hash: index_hash.trim().to_string()
+        },
+        _ => return Ok(None)
+    };
+    let files = match parser::parse_file_list(list) {
+        Ok(ref files) if files.len() == 0 => return Ok(None),
+        Ok(files) => files,
+        Err(_) => return Ok(None)
+    };
+    if saved.wineprefix != target.wineprefix {
+        Err(Stale::OtherWineprefix(saved.wineprefix))
+    } else if saved.index_hash != target.index_hash {
+        Err(Stale::IndexChanged)
+    } else {
+        Ok(Some(Plan { target: saved, files: files }))
+    }
+}
+
+/// Removes the saved plan, once the update it describes has been applied or it's no good any more
+pub fn clear() -> io::Result<()> {
+    clear_in(&staging_dir())
+}
+
+fn clear_in(dir: &Path) -> io::Result<()> {
+    for path in &[plan_path(dir), meta_path(dir)] {
+        match fs::remove_file(path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            other => try!(other)
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testutil::{scratch_dir, write, file_list, SAMPLE_INDEX};
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn test_plan_round_trip() {
+        let dir = scratch_dir("plan");
+        let target = PlanTarget::new(PathBuf::from("/home/tenno/.wine"), SAMPLE_INDEX);
+        assert_eq!(load_from(&dir, &target), Ok(None));
+
+        let files = file_list(SAMPLE_INDEX);
+        save_in(&dir, &files, &target).unwrap();
+        let plan = load_from(&dir, &target).unwrap().unwrap();
+        assert_eq!(plan.target, target);
+        assert_eq!(plan.files, files);
+
+        clear_in(&dir).unwrap();
+        assert_eq!(load_from(&dir, &target), Ok(None));
+        // Clearing when there's nothing there is fine too
+        clear_in(&dir).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_plan_other_target() {
+        let dir = scratch_dir("plan-target");
+        let target = PlanTarget::new(PathBuf::from("/home/tenno/.wine"), SAMPLE_INDEX);
+        save_in(&dir, &file_list(SAMPLE_INDEX), &target).unwrap();
+
+        let other_prefix = PlanTarget::new(PathBuf::from("/home/tenno/.wine-warframe"), SAMPLE_INDEX);
+        assert_eq!(load_from(&dir, &other_prefix), Err(Stale::OtherWineprefix(PathBuf::from("/home/tenno/.wine"))));
+        let new_index = format!("{}/Warframe.x64.exe.81E34ABBF3AEFAD7E56D157EDE08E178.lzma,7500000\r\n", SAMPLE_INDEX);
+        let other_index = PlanTarget::new(PathBuf::from("/home/tenno/.wine"), new_index.as_str());
+        assert_eq!(load_from(&dir, &other_index), Err(Stale::IndexChanged));
+        // Checking doesn't throw it away
+        assert!(load_from(&dir, &target).unwrap().is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_plan_corrupt() {
+        let dir = scratch_dir("plan-corrupt");
+        let target = PlanTarget::new(PathBuf::from("/home/tenno/.wine"), SAMPLE_INDEX);
+        save_in(&dir, &file_list(SAMPLE_INDEX), &target).unwrap();
+
+        write(&plan_path(&dir), b"/Warframe.exe.NOTAHASH.lzma,6998205\r\n");
+        assert_eq!(load_from(&dir, &target), Ok(None));
+        write(&plan_path(&dir), b"");
+        assert_eq!(load_from(&dir, &target), Ok(None));
+
+        // No meta file, ex from an older wfupdate
+        save_in(&dir, &file_list(SAMPLE_INDEX), &target).unwrap();
+        fs::remove_file(meta_path(&dir)).unwrap();
+        assert_eq!(load_from(&dir, &target), Ok(None));
+        write(&meta_path(&dir), b"/home/tenno/.wine\n");
+        assert_eq!(load_from(&dir, &target), Ok(None));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+//! Download Speed Limiting
+//!
+//! Keeps stage 1 downloads from using the whole connection, for updating in the background.
+//! All the downloads share one `RateLimiter`, so the limit is for all of them together rather than each one.
+//!
+//! The limit comes from `--limit-rate`, or the `network:maxrate` config value.
+
+use super::super::config;
+use super::downloader::DownloaderError;
+use super::source::ContentSource;
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The most that's read in one go from a throttled reader, so the speed stays smooth
+pub const MAX_CHUNK: usize = 16 * 1024;
+
+/// Parses a speed in bytes per second, like curl's `--limit-rate`.
+///
+/// Takes a number with an optional `K`, `M` or `G` suffix (powers of 1024), ex `500K` or `1.5M`.
+/// A `B` and/or `/s` after that is allowed too, ex `2MB/s`. Returns `None` for `0`, which means no limit.
+pub fn parse_rate(text: &str) -> Result<Option<u64>, String> {
+    let lower = text.trim().to_lowercase();
+    match config::parse_bytes(lower.trim_right_matches("/s")) {
+        Ok(0) => Ok(None),
+        Ok(rate) => Ok(Some(rate)),
+        Err(_) => Err(format!("\"{}\" isn't a download speed, use something like 500K or 2M", text))
+    }
+}
+
+/// The speed limit from `flag` (`--limit-rate`), or otherwise `network:maxrate`, if there is one
+pub fn from_config(config: &::ini::Ini, flag: Option<&str>) -> Result<Option<u64>, String> {
+    match flag.or(config.get_from(Some("network"), "maxrate")) {
+        Some(rate) => parse_rate(rate),
+        None => Ok(None)
+    }
+}
+
+/// A token bucket, shared between every thread that's downloading.
+///
+/// Tokens (bytes) refill at `rate` per second, up to `burst`. Taking more than there are puts the bucket in debt,
+/// and the taker sleeps until it's paid back, so everyone waiting gets a fair turn.
+pub struct RateLimiter {
+    rate: u64,
+    burst: u64,
+    /// Tokens available (negative when in debt) as of the `Instant`
+    state: Mutex<(f64, Instant)>
+}
+
+impl RateLimiter {
+    /// Makes a limiter for `rate` bytes per second, that allows bursts of up to a second's worth.
+    pub fn new(rate: u64) -> RateLimiter {
+        RateLimiter::with_burst(rate, rate)
+    }
+
+    /// Makes a limiter for `rate` bytes per second, that allows bursts of up to `burst` bytes.
+    pub fn with_burst(rate: u64, burst: u64) -> RateLimiter {
+        RateLimiter {
+            rate: cmp::max(rate, 1),
+            burst: burst,
+            state: Mutex::new((burst as f64, Instant::now()))
+        }
+    }
+
+    /// Uses up `bytes` worth of tokens, waiting for them if there aren't enough.
+    pub fn take(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.1);
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            let tokens = (state.0 + elapsed * self.rate as f64).min(self.burst as f64) - bytes as f64;
+            *state = (tokens, now);
+            if tokens >= 0.0 {
+                return;
+            }
+            -tokens / self.rate as f64
+        };
+        thread::sleep(Duration::new(wait as u64, (wait.fract() * 1e9) as u32));
+    }
+}
+
+/// A reader that doesn't go faster than its `RateLimiter` allows
+pub struct Throttled<R: Read> {
+    inner: R,
+    limiter: Arc<RateLimiter>
+}
+
+impl<R: Read> Throttled<R> {
+    /// Wraps `inner`, counting everything read from it against `limiter`
+    pub fn new(inner: R, limiter: Arc<RateLimiter>) -> Throttled<R> {
+        Throttled {
+            inner: inner,
+            limiter: limiter
+        }
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(buf.len(), MAX_CHUNK);
+        let read = try!(self.inner.read(&mut buf[..len]));
+        self.limiter.take(read as u64);
+        Ok(read)
+    }
+}
+
+/// A `ContentSource` whose files are all read through one `RateLimiter`
+pub struct ThrottledSource {
+    inner: Arc<ContentSource>,
+    limiter: Arc<RateLimiter>
+}
+
+impl ThrottledSource {
+    /// Limits everything read from `inner` to `rate` bytes per second in total
+    pub fn new(inner: Arc<ContentSource>, rate: u64) -> ThrottledSource {
+        ThrottledSource {
+            inner: inner,
+            limiter: Arc::new(RateLimiter::new(rate))
+        }
+    }
+}
+
+impl ContentSource for ThrottledSource {
+    fn open(&self, path: &str, offset: u64) -> Result<Box<Read>, DownloaderError> {
+        let reader = try!(self.inner.open(path, offset));
+        Ok(Box::new(Throttled::new(reader, self.limiter.clone())))
+    }
+
+    fn index_path(&self) -> String {
+        self.inner.index_path()
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+
+    fn retry_delay(&self, attempt: u32) -> Option<Duration> {
+        self.inner.retry_delay(attempt)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    pub fn test_parse_rate() {
+        assert_eq!(parse_rate("1000"), Ok(Some(1000)));
+        assert_eq!(parse_rate("500K"), Ok(Some(500 * 1024)));
+        assert_eq!(parse_rate("1.5m"), Ok(Some(1536 * 1024)));
+        assert_eq!(parse_rate("2MB/s"), Ok(Some(2 * 1024 * 1024)));
+        assert_eq!(parse_rate("0"), Ok(None));
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("-5K").is_err());
+    }
+
+    #[test]
+    pub fn test_rate_limiter_shared() {
+        // 100KB between two threads at 200KB/s, with only 10KB up front, has to take at least 0.45s
+        let limiter = Arc::new(RateLimiter::with_burst(200 * 1000, 10 * 1000));
+        let start = Instant::now();
+        let threads: Vec<_> = (0..2).map(|_| {
+            let limiter = limiter.clone();
+            thread::spawn(move || {
+                let mut reader = Throttled::new(io::repeat(0).take(50 * 1000), limiter);
+                io::copy(&mut reader, &mut io::sink()).unwrap()
+            })
+        }).collect();
+        let total: u64 = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        let elapsed = start.elapsed();
+        assert_eq!(total, 100 * 1000);
+        assert!(elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000 >= 400, "{:?}", elapsed);
+        assert!(elapsed.as_secs() < 2, "{:?}", elapsed);
+    }
+}
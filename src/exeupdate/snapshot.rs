@@ -0,0 +1,367 @@
+//! Stage 1 Rollback Snapshots
+//!
+//! Before a stage 1 update overwrites anything, the files it's about to replace get copied into a snapshot,
+//! so that a broken build (say, a `Warframe.exe` that doesn't run under Wine) can be undone with `wfupdate update rollback`.
+//!
+//! Snapshots live in `$XDG_DATA_HOME/wfupdate/snapshots/<unix timestamp>/`. Each one holds the old files,
+//! named by their MD5 hash, and a `manifest.txt` starting with the wineprefix they came from,
+//! followed by what was replaced, one entry per line:
+//!
+//! ```text
+//! wineprefix=/home/user/.wine-warframe
+//! /Warframe.exe,3BB594902B2E8037901ED9B2419E8FD5
+//! /Tools/Launcher.exe,F336FD22FDF21024C75FF46FE8F7A06E
+//! ```
+//!
+//! Only files that existed before the update are recorded, files the update adds are left alone on rollback.
+//! A snapshot is only ever restored into the wineprefix it was taken from.
+
+use super::super::{config, paths};
+use super::update::{CommitError, UpdateBatch};
+use hex::{FromHex, ToHex};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use time;
+
+/// A file saved in a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    /// The path as it appears in the index, ex `/Warframe.exe`
+    pub disk_path: String,
+    /// The MD5 hash of the saved file
+    pub md5sum: Vec<u8>
+}
+
+/// A saved set of files from before a stage 1 update.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// The snapshot's directory
+    pub path: PathBuf,
+    /// The wineprefix the files were saved from, `None` for snapshots that don't say
+    pub wineprefix: Option<PathBuf>,
+    /// The files saved in the snapshot
+    pub entries: Vec<SnapshotEntry>
+}
+
+impl Snapshot {
+    /// Whether the snapshot was taken from `wineprefix`
+    pub fn is_for(&self, wineprefix: &Path) -> bool {
+        self.wineprefix.as_ref().map_or(false, |p| p == wineprefix)
+    }
+}
+
+const WINEPREFIX_KEY: &'static str = "wineprefix=";
+
+/// The directory all snapshots are kept in
+pub fn snapshots_dir() -> PathBuf {
+    config::data_dir("snapshots")
+}
+
+fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join("manifest.txt")
+}
+
+/// Saves the current on-disk versions of `files` in the current wineprefix into a new snapshot.
+///
+/// Files that don't exist yet are skipped. Returns `None` if there was nothing to save.
+/// If saving any of them fails, nothing is left behind.
+pub fn create(files: &[super::File]) -> io::Result<Option<Snapshot>> {
+    create_in(&snapshots_dir(), paths::plat::wineprefix(), files, |file| paths::realize_path(file.disk_path.clone()))
+}
+
+/// `create()`, into `root` instead of `snapshots_dir()`, with `realize` working out where each file is
+fn create_in<F>(root: &Path, wineprefix: PathBuf, files: &[super::File], realize: F) -> io::Result<Option<Snapshot>>
+    where F: Fn(&super::File) -> Option<PathBuf> {
+    let mut name = time::now().to_timespec().sec;
+    // Two updates in the same second is unlikely, but not impossible.
+    while root.join(name.to_string()).exists() {
+        name += 1;
+    }
+    let dir = root.join(name.to_string());
+    match save_files(&dir, &wineprefix, files, realize) {
+        Ok(entries) => if entries.len() > 0 {
+            Ok(Some(Snapshot {
+                path: dir,
+                wineprefix: Some(wineprefix),
+                entries: entries
+            }))
+        } else {
+            Ok(None)
+        },
+        Err(err) => {
+            // Don't leave half a snapshot behind: the files copied so far, and the placeholder for the one that failed
+            let _ = fs::remove_dir_all(&dir);
+            Err(err)
+        }
+    }
+}
+
+/// Copies each of `files` that exists into `dir`, and writes the manifest. `dir` is only created if there's something to save.
+fn save_files<F>(dir: &Path, wineprefix: &Path, files: &[super::File], realize: F) -> io::Result<Vec<SnapshotEntry>>
+    where F: Fn(&super::File) -> Option<PathBuf> {
+    let mut entries = vec![];
+    for file in files {
+        let realized = match realize(file) {
+            Some(p) => p,
+            None => continue
+        };
+        let mut original = match File::open(&realized) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e)
+        };
+        if entries.len() == 0 {
+            try!(fs::create_dir_all(dir));
+        }
+        // The hash isn't known until the whole file's been read, so it's copied under a placeholder name first.
+        let partial = dir.join("partial");
+        let (_, md5sum) = try!(File::create(&partial).and_then(|mut saved| {
+            super::downloader::copy_hashed(&mut original, &mut saved)
+        }));
+        try!(fs::rename(&partial, dir.join(md5sum.to_hex().to_uppercase())));
+        entries.push(SnapshotEntry {
+            disk_path: file.disk_path.clone(),
+            md5sum: md5sum
+        });
+    }
+
+    if entries.len() > 0 {
+        let mut manifest = try!(File::create(manifest_path(dir)));
+        try!(writeln!(manifest, "{}{}", WINEPREFIX_KEY, wineprefix.display()));
+        for entry in &entries {
+            try!(writeln!(manifest, "{},{}", entry.disk_path, entry.md5sum.to_hex().to_uppercase()));
+        }
+        try!(manifest.sync_all());
+    }
+    Ok(entries)
+}
+
+/// Reads a snapshot's manifest
+pub fn load(dir: PathBuf) -> io::Result<Snapshot> {
+    let manifest = try!(File::open(manifest_path(&dir)));
+    let mut wineprefix = None;
+    let mut entries = vec![];
+    for line in BufReader::new(manifest).lines() {
+        let line = try!(line);
+        let line = line.trim();
+        if line.starts_with(WINEPREFIX_KEY) {
+            wineprefix = Some(PathBuf::from(line.split_at(WINEPREFIX_KEY.len()).1));
+            continue;
+        }
+        let pos = match line.rfind(',') {
+            Some(p) => p,
+            None => continue
+        };
+        let (disk_path, hash) = line.split_at(pos);
+        let md5sum = match Vec::from_hex(hash.split_at(1).1) {
+            Ok(k) => k,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad hash in snapshot manifest: {}", line)))
+        };
+        entries.push(SnapshotEntry {
+            disk_path: disk_path.to_string(),
+            md5sum: md5sum
+        });
+    }
+    Ok(Snapshot {
+        path: dir,
+        wineprefix: wineprefix,
+        entries: entries
+    })
+}
+
+/// Lists every snapshot directory, oldest first
+pub fn list() -> io::Result<Vec<PathBuf>> {
+    list_in(&snapshots_dir())
+}
+
+fn list_in(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut out: Vec<(i64, PathBuf)> = vec![];
+    for entry in try!(fs::read_dir(root)) {
+        let entry = try!(entry);
+        let timestamp = match entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+            Some(t) => t,
+            None => continue
+        };
+        if manifest_path(&entry.path()).exists() {
+            out.push((timestamp, entry.path()));
+        }
+    }
+    out.sort();
+    Ok(out.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Gets the most recent snapshot taken from `wineprefix`, if there is one
+pub fn latest(wineprefix: &Path) -> io::Result<Option<Snapshot>> {
+    for dir in try!(list()).into_iter().rev() {
+        let snapshot = try!(load(dir));
+        if snapshot.is_for(wineprefix) {
+            return Ok(Some(snapshot));
+        }
+    }
+    Ok(None)
+}
+
+/// Puts every file in a snapshot back where it came from, then deletes the snapshot.
+///
+/// The files are restored as a single `UpdateBatch`, so either all of them come back or none do.
+/// Snapshots taken from a different wineprefix than the current one are refused.
+/// Anything that goes wrong before a file has been changed is a `CommitError::RolledBack`.
+pub fn restore(snapshot: Snapshot) -> Result<(), CommitError> {
+    restore_with(snapshot, &paths::plat::wineprefix(), |entry| paths::realize_path(entry.disk_path.clone()))
+}
+
+/// `restore()` into `wineprefix`, with `realize` working out where each file goes
+fn restore_with<F>(snapshot: Snapshot, wineprefix: &Path, realize: F) -> Result<(), CommitError>
+    where F: Fn(&SnapshotEntry) -> Option<PathBuf> {
+    let batch = try!(stage_restore(&snapshot, wineprefix, realize).map_err(CommitError::RolledBack));
+    let result = batch.commit();
+    if let Err(CommitError::RolledBack(_)) = result {
+        return result;
+    }
+    let _ = fs::remove_dir_all(snapshot.path);
+    result
+}
+
+fn stage_restore<F>(snapshot: &Snapshot, wineprefix: &Path, realize: F) -> io::Result<UpdateBatch>
+    where F: Fn(&SnapshotEntry) -> Option<PathBuf> {
+    if !snapshot.is_for(wineprefix) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("the snapshot is from {}, not {}",
+                                          snapshot.wineprefix.as_ref().map_or(String::from("an unknown wineprefix"),
+                                                                              |p| p.display().to_string()),
+                                          wineprefix.display())));
+    }
+    let mut batch = UpdateBatch::new();
+    for entry in &snapshot.entries {
+        let mut saved = try!(File::open(snapshot.path.join(entry.md5sum.to_hex().to_uppercase())));
+        let realized = match realize(entry) {
+            Some(p) => p,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "can't find the Warframe install"))
+        };
+        try!(batch.stage_from(realized, &mut saved));
+    }
+    Ok(batch)
+}
+
+/// Deletes a snapshot, ex one taken for an update that then failed
+pub fn delete(snapshot: Snapshot) -> io::Result<()> {
+    fs::remove_dir_all(snapshot.path)
+}
+
+/// Deletes the oldest snapshots taken from `wineprefix`, keeping only the newest `keep`.
+///
+/// Other wineprefixes' snapshots are left alone.
+pub fn prune(keep: usize, wineprefix: &Path) -> io::Result<()> {
+    prune_in(&snapshots_dir(), keep, wineprefix)
+}
+
+fn prune_in(root: &Path, keep: usize, wineprefix: &Path) -> io::Result<()> {
+    let mut snapshots = vec![];
+    for dir in try!(list_in(root)) {
+        let snapshot = try!(load(dir));
+        if snapshot.is_for(wineprefix) {
+            snapshots.push(snapshot);
+        }
+    }
+    if snapshots.len() > keep {
+        let old = snapshots.len() - keep;
+        for snapshot in snapshots.into_iter().take(old) {
+            try!(delete(snapshot));
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testutil::{scratch_dir, write, read, test_file};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// Realizes index paths under `install`, as if it were the game directory
+    fn under(install: &Path, disk_path: &str) -> Option<PathBuf> {
+        Some(install.join(disk_path.trim_left_matches('/')))
+    }
+
+    fn snapshot(root: &Path, wineprefix: &Path, install: &Path, files: &[super::super::File]) -> Option<Snapshot> {
+        create_in(root, wineprefix.to_path_buf(), files, |file| under(install, &file.disk_path)).unwrap()
+    }
+
+    #[test]
+    pub fn test_snapshot_create_restore() {
+        let dir = scratch_dir("snapshot");
+        let (root, install) = (dir.join("snapshots"), dir.join("install"));
+        let wineprefix = PathBuf::from("/home/tenno/.wine");
+        write(&install.join("Warframe.exe"), b"old warframe");
+        let files = vec![test_file("/Warframe.exe", b"new warframe"), test_file("/Tools/Launcher.exe", b"launcher")];
+
+        let saved = snapshot(&root, &wineprefix, &install, &files).unwrap();
+        // The launcher doesn't exist yet, so there's nothing to save for it
+        assert_eq!(saved.entries.len(), 1);
+        assert_eq!(saved.entries[0].disk_path, "/Warframe.exe");
+        let loaded = load(saved.path.clone()).unwrap();
+        assert_eq!(loaded.entries, saved.entries);
+        assert_eq!(loaded.wineprefix, Some(wineprefix.clone()));
+        assert_eq!(list_in(&root).unwrap(), vec![saved.path.clone()]);
+
+        write(&install.join("Warframe.exe"), b"new warframe");
+        // Not into some other wineprefix
+        let other = PathBuf::from("/home/tenno/.wine-other");
+        match restore_with(loaded.clone(), &other, |entry| under(&install, &entry.disk_path)) {
+            Err(CommitError::RolledBack(_)) => {},
+            other => panic!("expected the restore to be refused, got {:?}", other)
+        }
+        assert_eq!(read(&install.join("Warframe.exe")), b"new warframe");
+
+        restore_with(loaded, &wineprefix, |entry| under(&install, &entry.disk_path)).unwrap();
+        assert_eq!(read(&install.join("Warframe.exe")), b"old warframe");
+        assert!(!saved.path.exists());
+
+        // Nothing to save at all
+        assert!(snapshot(&root, &wineprefix, &install, &files[1..]).is_none());
+        assert_eq!(list_in(&root).unwrap().len(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_snapshot_create_failure() {
+        let dir = scratch_dir("snapshot-failure");
+        let (root, install) = (dir.join("snapshots"), dir.join("install"));
+        fs::create_dir_all(&root).unwrap();
+        write(&install.join("Warframe.exe"), b"old warframe");
+        // Opening a directory works, reading from it doesn't
+        fs::create_dir_all(install.join("Tools/Launcher.exe")).unwrap();
+        let files = vec![test_file("/Warframe.exe", b"new warframe"), test_file("/Tools/Launcher.exe", b"launcher")];
+
+        assert!(create_in(&root, PathBuf::from("/home/tenno/.wine"), &files, |file| under(&install, &file.disk_path)).is_err());
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_snapshot_prune() {
+        let dir = scratch_dir("snapshot-prune");
+        let (root, install) = (dir.join("snapshots"), dir.join("install"));
+        let (ours, theirs) = (PathBuf::from("/home/tenno/.wine"), PathBuf::from("/home/tenno/.wine-other"));
+        write(&install.join("Warframe.exe"), b"warframe");
+        let files = vec![test_file("/Warframe.exe", b"new warframe")];
+        let mut taken = vec![];
+        for wineprefix in &[&ours, &theirs, &ours, &ours] {
+            taken.push(snapshot(&root, wineprefix, &install, &files).unwrap().path);
+        }
+
+        prune_in(&root, 1, &ours).unwrap();
+        assert_eq!(list_in(&root).unwrap(), vec![taken[1].clone(), taken[3].clone()]);
+        // Keeping more than there are doesn't delete anything
+        prune_in(&root, 5, &theirs).unwrap();
+        prune_in(&root, 0, &theirs).unwrap();
+        assert_eq!(list_in(&root).unwrap(), vec![taken[3].clone()]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
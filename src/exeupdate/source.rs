@@ -0,0 +1,517 @@
+//! Warframe Content Sources
+//!
+//! A `ContentSource` is somewhere the compressed stage 1 files (and the index) can be read from.
+//! Paths are always given the way they appear in the index, ex `/Tools/Launcher.exe.F336FD22FDF21024C75FF46FE8F7A06E.lzma`.
+//!
+//! There are three kinds:
+//!
+//! * `HttpSource`, a web server laid out like `http://origin.warframe.com/` (the default),
+//!   or several of them to fail over between
+//! * `DirectorySource`, a local copy of origin, ex `/srv/warframe/Tools/Launcher.exe.F336FD22FDF21024C75FF46FE8F7A06E.lzma`
+//! * `TarballSource`, a `.tar` of such a directory
+//!
+//! Local copies keep the index at `/index.txt.lzma`.
+//!
+//! `from_spec()` picks the right one from a string, which is how the `update:source` config key and `--source` flag work.
+//! By default, files come from origin.warframe.com, falling back to content.warframe.com (which serves the same paths).
+
+use super::downloader::DownloaderError;
+use super::network::{self, NetworkConfig};
+use hyper::{Client, Url};
+use hyper::header::{Headers, Range, ByteRangeSpec};
+use hyper::status::StatusCode;
+use rand::Rng;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::fs::File;
+use std::path::PathBuf;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use std::cmp;
+
+/// The official Warframe content server
+pub static WARFRAME_CONTENT_URL: &'static str = "http://origin.warframe.com/";
+/// Another official server with the same files, tried if origin doesn't work out
+pub static WARFRAME_FALLBACK_URL: &'static str = "http://content.warframe.com/";
+
+/// Somewhere stage 1 files can be read from.
+pub trait ContentSource: Send + Sync {
+    /// Opens the compressed file at `path`, skipping the first `offset` bytes of it.
+    ///
+    /// `offset` is used to resume partial downloads. Asking for an offset at or past the end of the file gives an empty reader.
+    fn open(&self, path: &str, offset: u64) -> Result<Box<Read>, DownloaderError>;
+
+    /// The path of the compressed index, `/index.txt.lzma` unless the source says otherwise.
+    fn index_path(&self) -> String {
+        String::from("/index.txt.lzma")
+    }
+
+    /// A human-readable description of where files are coming from, ex the base URL.
+    fn describe(&self) -> String;
+
+    /// How long to wait before opening a file again after the `attempt`th read of it (counting from 1) failed partway through,
+    /// or `None` to give up.
+    ///
+    /// Only worth retrying for sources that can fail on their own, so by default it's always `None`.
+    fn retry_delay(&self, attempt: u32) -> Option<Duration> {
+        let _ = attempt;
+        None
+    }
+}
+
+fn is_url(spec: &str) -> bool {
+    spec.starts_with("http://") || spec.starts_with("https://")
+}
+
+/// Picks a content source based on a user-supplied string.
+///
+/// * `http://...` or `https://...` is an `HttpSource`. Several URLs separated with `;` are tried in that order.
+/// * a path ending in `.tar` is a `TarballSource`
+/// * anything else is a `DirectorySource`, and has to exist
+///
+/// `jobs` is how many downloads will be running at once, and `network` how to talk to servers. Both only matter for HTTP.
+pub fn from_spec(spec: &str, jobs: usize, network: NetworkConfig) -> Result<Arc<ContentSource>, String> {
+    if is_url(spec) {
+        let urls: Vec<&str> = spec.split(';').map(str::trim).filter(|url| url.len() > 0).collect();
+        if let Some(url) = urls.iter().find(|url| !is_url(url)) {
+            return Err(format!("{} isn't a URL, only URLs can be listed with ;", url));
+        }
+        Ok(Arc::new(try!(HttpSource::with_config(urls, jobs, network))))
+    } else if spec.ends_with(".tar") {
+        match TarballSource::new(PathBuf::from(spec)) {
+            Ok(source) => Ok(Arc::new(source)),
+            Err(err) => Err(format!("couldn't read {}: {}", spec, err))
+        }
+    } else {
+        let path = PathBuf::from(spec);
+        if path.is_dir() {
+            Ok(Arc::new(DirectorySource::new(path)))
+        } else {
+            Err(format!("{} isn't a URL, a directory, or a .tar file", spec))
+        }
+    }
+}
+
+/// Picks the content source from the `update:source` config key, with `spec` (from `--source`) taking priority.
+///
+/// Falls back to `WARFRAME_CONTENT_URL`, then `WARFRAME_FALLBACK_URL`.
+/// HTTP sources use the `[network]` settings, and print what they're doing if `verbose`.
+pub fn from_config(config: &::ini::Ini, spec: Option<&str>, jobs: usize, verbose: bool) -> Result<Arc<ContentSource>, String> {
+    let default = format!("{};{}", WARFRAME_CONTENT_URL, WARFRAME_FALLBACK_URL);
+    let spec = match spec {
+        Some(spec) => spec,
+        None => config.get_from(Some("update"), "source").unwrap_or(default.as_str())
+    };
+    let mut network = try!(NetworkConfig::from_config(config));
+    network.verbose = verbose;
+    from_spec(spec, jobs, network)
+}
+
+
+/// A web server laid out like origin.warframe.com, or several to fail over between
+///
+/// Each try at a request goes through the servers in order until one of them works.
+/// If none of them do, and at least one failed in a way that might go away (see `network::is_transient()`),
+/// there's another round after a while, up to `RetryPolicy::attempts` rounds.
+///
+/// A server that breaks off a download partway through goes to the back of the line,
+/// so resuming the download (see `ContentSource::retry_delay()`) tries the next one first.
+pub struct HttpSource {
+    base_urls: Vec<String>,
+    /// Which of `base_urls` to try first
+    first: Arc<AtomicUsize>,
+    client: Client,
+    network: NetworkConfig
+}
+
+/// A response body that sends its `HttpSource` to the next server if it fails partway through
+struct FailoverReader {
+    inner: Box<Read>,
+    server: usize,
+    servers: usize,
+    first: Arc<AtomicUsize>
+}
+
+impl Read for FailoverReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        if result.is_err() {
+            // Unless another download already moved on from this server
+            let _ = self.first.compare_exchange(self.server, (self.server + 1) % self.servers, Ordering::SeqCst, Ordering::SeqCst);
+        }
+        result
+    }
+}
+
+impl HttpSource {
+    /// Makes a new HTTP source with the default network settings. `jobs` is how many connections to keep around for reuse.
+    ///
+    /// Panics if OpenSSL can't be set up, which only happens if something's very wrong with it.
+    pub fn new(base_url: &str, jobs: usize) -> Self {
+        HttpSource::with_config(vec![base_url], jobs, NetworkConfig::default()).unwrap()
+    }
+
+    /// Makes a new HTTP source that tries each of `base_urls` in order.
+    ///
+    /// Fails if `network.ca_file` can't be loaded.
+    pub fn with_config(base_urls: Vec<&str>, jobs: usize, network: NetworkConfig) -> Result<Self, String> {
+        Ok(HttpSource {
+            base_urls: base_urls.into_iter().map(|url| url.trim_right_matches('/').to_string()).collect(),
+            first: Arc::new(AtomicUsize::new(0)),
+            client: try!(network.client(jobs)),
+            network: network
+        })
+    }
+
+    /// One try at getting `path` from the server at `base_url`
+    fn open_from(&self, base_url: &str, path: &str, offset: u64) -> Result<Box<Read>, DownloaderError> {
+        let url = match Url::parse(format!("{}/{}", base_url, path.trim_left_matches('/')).as_str()) {
+            Ok(url) => url,
+            Err(err) => return Err(DownloaderError::HyperError(From::from(err)))
+        };
+        let mut headers = Headers::new();
+        if offset > 0 {
+            headers.set(Range::Bytes(vec![ByteRangeSpec::AllFrom(offset)]));
+        }
+        if let Some(auth) = self.network.proxy_authorization(&url) {
+            headers.set_raw("Proxy-Authorization", vec![auth.into_bytes()]);
+        }
+        let mut res = match self.client.get(url).headers(headers).send() {
+            Ok(res) => res,
+            Err(err) => return Err(DownloaderError::HyperError(err))
+        };
+        match res.status {
+            StatusCode::PartialContent if offset > 0 => Ok(Box::new(res)),
+            // Asked for everything past the end, there's nothing left to read.
+            StatusCode::RangeNotSatisfiable if offset > 0 => Ok(Box::new(io::empty())),
+            StatusCode::Ok => {
+                // The server ignored the Range and sent the whole thing, skip what we already have.
+                if offset > 0 {
+                    try!(io::copy(&mut (&mut res).take(offset), &mut io::sink()));
+                }
+                Ok(Box::new(res))
+            },
+            status => Err(DownloaderError::HttpStatus(status))
+        }
+    }
+}
+
+impl ContentSource for HttpSource {
+    fn open(&self, path: &str, offset: u64) -> Result<Box<Read>, DownloaderError> {
+        let attempts = cmp::max(self.network.retry.attempts, 1);
+        let mut last_error = None;
+        for attempt in 1..attempts + 1 {
+            let mut transient = false;
+            let first = self.first.load(Ordering::SeqCst);
+            for i in 0..self.base_urls.len() {
+                let server = (first + i) % self.base_urls.len();
+                let base_url = &self.base_urls[server];
+                if self.network.verbose {
+                    println!("Requesting {}/{} (try {}/{})", base_url, path.trim_left_matches('/'), attempt, attempts);
+                }
+                match self.open_from(base_url.as_str(), path, offset) {
+                    Ok(res) => return Ok(Box::new(FailoverReader {
+                        inner: res,
+                        server: server,
+                        servers: self.base_urls.len(),
+                        first: self.first.clone()
+                    })),
+                    Err(err) => {
+                        if self.network.verbose {
+                            println!("{} failed: {:?}", base_url, err);
+                        }
+                        transient |= network::is_transient(&err);
+                        last_error = Some(err);
+                    }
+                }
+            }
+            if !transient || attempt == attempts {
+                break;
+            }
+            let delay = self.network.retry.delay(attempt);
+            if self.network.verbose {
+                println!("Retrying {} in {}s", path, delay.as_secs());
+            }
+            thread::sleep(delay);
+        }
+        Err(last_error.unwrap())
+    }
+
+    /// origin.warframe.com serves the index at `/origin/XXXXXXXX/index.txt.lzma`,
+    /// where `XXXXXXXX` is eight random hexadecimal characters (for cache busting).
+    fn index_path(&self) -> String {
+        let mut rng = ::rand::thread_rng();
+        format!("/origin/{:08X}/index.txt.lzma", rng.gen::<u32>())
+    }
+
+    fn describe(&self) -> String {
+        self.base_urls.join(", ")
+    }
+
+    fn retry_delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt < self.network.retry.attempts {
+            let delay = self.network.retry.delay(attempt);
+            if self.network.verbose {
+                println!("Download broke off, resuming in {}s", delay.as_secs());
+            }
+            Some(delay)
+        } else {
+            None
+        }
+    }
+}
+
+
+/// A local directory laid out like origin.warframe.com
+pub struct DirectorySource {
+    root: PathBuf
+}
+
+impl DirectorySource {
+    /// Makes a new directory source rooted at `root`
+    pub fn new(root: PathBuf) -> Self {
+        DirectorySource {
+            root: root
+        }
+    }
+}
+
+impl ContentSource for DirectorySource {
+    fn open(&self, path: &str, offset: u64) -> Result<Box<Read>, DownloaderError> {
+        let mut f = try!(File::open(self.root.join(path.trim_left_matches('/'))));
+        try!(f.seek(SeekFrom::Start(offset)));
+        Ok(Box::new(f))
+    }
+
+    fn describe(&self) -> String {
+        self.root.to_string_lossy().into_owned()
+    }
+}
+
+
+/// A `.tar` of a directory laid out like origin.warframe.com
+///
+/// The archive is indexed once when it's opened, after that files are read straight out of it.
+/// Plain ustar archives and GNU long names are supported.
+pub struct TarballSource {
+    path: PathBuf,
+    /// archive path (without a leading `/` or `./`) -> (where the data starts, size)
+    entries: HashMap<String, (u64, u64)>
+}
+
+/// Reads a NUL-terminated string out of a tar header field
+fn tar_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Reads an octal number out of a tar header field
+fn tar_octal(field: &[u8]) -> io::Result<u64> {
+    let text = tar_str(field);
+    match u64::from_str_radix(text.trim_matches(|c| c == ' ' || c == '\0'), 8) {
+        Ok(n) => Ok(n),
+        Err(_) if text.trim().len() == 0 => Ok(0),
+        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad number in tar header: {:?}", text)))
+    }
+}
+
+fn tar_key(name: &str) -> String {
+    name.trim_left_matches("./").trim_left_matches('/').to_string()
+}
+
+impl TarballSource {
+    /// Opens and indexes the archive at `path`
+    pub fn new(path: PathBuf) -> io::Result<Self> {
+        let mut f = try!(File::open(&path));
+        let mut entries = HashMap::new();
+        let mut header = [0u8; 512];
+        let mut pos = 0u64;
+        let mut long_name: Option<String> = None;
+        loop {
+            try!(f.seek(SeekFrom::Start(pos)));
+            try!(f.read_exact(&mut header));
+            if header.iter().all(|&b| b == 0) {
+                // End of archive
+                break;
+            }
+            let size = try!(tar_octal(&header[124..136]));
+            let data = pos + 512;
+            match header[156] {
+                b'0' | 0 => {
+                    let name = match long_name.take() {
+                        Some(name) => name,
+                        None => {
+                            let name = tar_str(&header[0..100]);
+                            let prefix = if &header[257..262] == b"ustar" { tar_str(&header[345..500]) } else { String::new() };
+                            if prefix.len() > 0 { format!("{}/{}", prefix, name) } else { name }
+                        }
+                    };
+                    entries.insert(tar_key(name.as_str()), (data, size));
+                },
+                b'L' => {
+                    // GNU long name, the data is the name of the next entry
+                    let mut name = vec![0u8; size as usize];
+                    try!(f.read_exact(&mut name));
+                    long_name = Some(tar_str(&name));
+                },
+                _ => {
+                    long_name = None;
+                }
+            }
+            pos = data + (size + 511) / 512 * 512;
+        }
+        Ok(TarballSource {
+            path: path,
+            entries: entries
+        })
+    }
+}
+
+impl ContentSource for TarballSource {
+    fn open(&self, path: &str, offset: u64) -> Result<Box<Read>, DownloaderError> {
+        let &(start, size) = match self.entries.get(&tar_key(path)) {
+            Some(entry) => entry,
+            None => return Err(DownloaderError::IOError(io::Error::new(io::ErrorKind::NotFound,
+                                                                       format!("{} isn't in {:?}", path, self.path))))
+        };
+        let offset = cmp::min(offset, size);
+        let mut f = try!(File::open(&self.path));
+        try!(f.seek(SeekFrom::Start(start + offset)));
+        Ok(Box::new(f.take(size - offset)))
+    }
+
+    fn describe(&self) -> String {
+        self.path.to_string_lossy().into_owned()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::network::{NetworkConfig, RetryPolicy};
+    use hyper::server::{Server, Request, Response, Listening};
+    use hyper::status::StatusCode;
+    use super::super::testutil::{scratch_dir, write};
+    use std::fs;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    fn read_all(source: &ContentSource, path: &str, offset: u64) -> Vec<u8> {
+        let mut out = vec![];
+        source.open(path, offset).unwrap().read_to_end(&mut out).unwrap();
+        out
+    }
+
+    /// Builds a minimal ustar header, without a checksum since we don't check it.
+    fn tar_header(name: &str, size: usize) -> Vec<u8> {
+        let mut header = vec![0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = format!("{:011o}", size);
+        header[124..135].copy_from_slice(size.as_bytes());
+        header[156] = b'0';
+        header[257..262].copy_from_slice(b"ustar");
+        header
+    }
+
+    /// A server that answers the first `failures` requests with a 503, and sends `body` after that.
+    ///
+    /// Returns the server, its base URL, and how many requests it's had.
+    fn flaky_server(failures: usize, body: &'static [u8]) -> (Listening, String, Arc<Mutex<usize>>) {
+        let requests = Arc::new(Mutex::new(0));
+        let count = requests.clone();
+        let listening = Server::http("127.0.0.1:0").unwrap().handle(move |_: Request, mut res: Response| {
+            let mut count = count.lock().unwrap();
+            *count += 1;
+            if *count <= failures {
+                *res.status_mut() = StatusCode::ServiceUnavailable;
+                res.send(b"").unwrap();
+            } else {
+                res.send(body).unwrap();
+            }
+        }).unwrap();
+        let base = format!("http://{}/", listening.socket);
+        (listening, base, requests)
+    }
+
+    fn quick_retries(attempts: u32) -> NetworkConfig {
+        NetworkConfig {
+            retry: RetryPolicy {
+                attempts: attempts,
+                backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(50)
+            },
+            ..NetworkConfig::default()
+        }
+    }
+
+    #[test]
+    pub fn test_http_retry() {
+        let (mut listening, base, requests) = flaky_server(2, b"launcher");
+        let source = HttpSource::with_config(vec![base.as_str()], 1, quick_retries(3)).unwrap();
+        assert_eq!(read_all(&source, "/Tools/Launcher.exe.lzma", 0), b"launcher");
+        assert_eq!(*requests.lock().unwrap(), 3);
+        drop(source);
+
+        // Out of tries
+        *requests.lock().unwrap() = 0;
+        let source = HttpSource::with_config(vec![base.as_str()], 1, quick_retries(1)).unwrap();
+        match source.open("/Tools/Launcher.exe.lzma", 0) {
+            Err(DownloaderError::HttpStatus(StatusCode::ServiceUnavailable)) => {},
+            _ => panic!("expected a 503")
+        }
+        drop(source);
+        listening.close().unwrap();
+    }
+
+    #[test]
+    pub fn test_http_failover() {
+        // Nothing's listening on this port once the listener's gone
+        let dead = format!("http://{}/", TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+        let (mut listening, base, requests) = flaky_server(0, b"launcher");
+        let source = HttpSource::with_config(vec![dead.as_str(), base.as_str()], 1, quick_retries(1)).unwrap();
+        assert_eq!(read_all(&source, "/Tools/Launcher.exe.lzma", 0), b"launcher");
+        assert_eq!(*requests.lock().unwrap(), 1);
+        drop(source);
+        listening.close().unwrap();
+    }
+
+    #[test]
+    pub fn test_directory_source() {
+        let dir = scratch_dir("dirsource");
+        write(&dir.join("Tools/Launcher.exe.lzma"), b"launcher");
+
+        let source = DirectorySource::new(dir.clone());
+        assert_eq!(read_all(&source, "/Tools/Launcher.exe.lzma", 0), b"launcher");
+        assert_eq!(read_all(&source, "/Tools/Launcher.exe.lzma", 3), b"ncher");
+        assert!(source.open("/Warframe.exe.lzma", 0).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_tarball_source() {
+        let dir = scratch_dir("tarsource");
+        let mut tar = vec![];
+        tar.extend(tar_header("./Tools/Launcher.exe.lzma", 8));
+        tar.extend(b"launcher".iter().cloned());
+        tar.extend(vec![0u8; 504]);
+        tar.extend(tar_header("index.txt.lzma", 5));
+        tar.extend(b"index".iter().cloned());
+        tar.extend(vec![0u8; 507]);
+        tar.extend(vec![0u8; 1024]);
+        write(&dir.join("origin.tar"), tar.as_slice());
+
+        let source = TarballSource::new(dir.join("origin.tar")).unwrap();
+        assert_eq!(read_all(&source, "/Tools/Launcher.exe.lzma", 0), b"launcher");
+        assert_eq!(read_all(&source, "/Tools/Launcher.exe.lzma", 6), b"er");
+        assert_eq!(read_all(&source, "/Tools/Launcher.exe.lzma", 100), b"");
+        assert_eq!(read_all(&source, source.index_path().as_str(), 0), b"index");
+        assert!(source.open("/Warframe.exe.lzma", 0).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+//! Stage 1 Index Statistics
+//!
+//! Totals up the files in an index by category, by top-level directory and by architecture,
+//! for `wfupdate index stats`.
+//!
+//! All sizes are the compressed sizes listed in the index, the index doesn't say how big files are once extracted.
+
+use super::parser::{FileType, Rules};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A count of files and their total compressed size
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tally {
+    /// Number of files
+    pub files: usize,
+    /// Total compressed size, in bytes
+    pub bytes: u64
+}
+
+impl Tally {
+    /// Counts another file
+    pub fn add(&mut self, file: &super::File) {
+        self.files += 1;
+        self.bytes += file.size;
+    }
+}
+
+/// Which architecture a file is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Arch {
+    /// 32-bit only, ex `/Tools/Windows/x86/steam_api.dll`
+    X86,
+    /// 64-bit only, ex `/Warframe.x64.exe`
+    X64,
+    /// Used by both
+    Any
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            Arch::X86 => "x86",
+            Arch::X64 => "x64",
+            Arch::Any => "any"
+        })
+    }
+}
+
+/// Works out which architecture a file is for from its path
+pub fn architecture(file: &super::File) -> Arch {
+    if file.disk_path.contains("x64") {
+        Arch::X64
+    } else if file.disk_path.contains("x86") {
+        Arch::X86
+    } else {
+        Arch::Any
+    }
+}
+
+/// The top-level directory a file is in, ex `/Tools` for `/Tools/Launcher.exe`, or `/` for `/Warframe.exe`
+pub fn top_level_dir(file: &super::File) -> String {
+    match file.disk_path.trim_left_matches('/').find('/') {
+        Some(pos) => format!("/{}", file.disk_path.trim_left_matches('/').split_at(pos).0),
+        None => String::from("/")
+    }
+}
+
+/// Totals for a whole index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexStats {
+    /// Per category, in `FileType::all()` order, leaving out empty ones
+    pub by_type: Vec<(FileType, Tally)>,
+    /// Per top-level directory, sorted by name
+    pub by_dir: Vec<(String, Tally)>,
+    /// Per architecture
+    pub by_arch: Vec<(Arch, Tally)>,
+    /// Everything
+    pub total: Tally
+}
+
+/// Totals up an index, categorizing files with `rules`
+pub fn stats(files: &[super::File], rules: &Rules) -> IndexStats {
+    let mut by_type: BTreeMap<usize, Tally> = BTreeMap::new();
+    let mut by_dir: BTreeMap<String, Tally> = BTreeMap::new();
+    let mut by_arch: BTreeMap<Arch, Tally> = BTreeMap::new();
+    let mut total = Tally::default();
+    let types = FileType::all();
+    for file in files {
+        let category = rules.categorize(file);
+        let type_pos = types.iter().position(|t| *t == category).unwrap();
+        by_type.entry(type_pos).or_insert_with(Tally::default).add(file);
+        by_dir.entry(top_level_dir(file)).or_insert_with(Tally::default).add(file);
+        by_arch.entry(architecture(file)).or_insert_with(Tally::default).add(file);
+        total.add(file);
+    }
+    IndexStats {
+        by_type: by_type.into_iter().map(|(pos, tally)| (types[pos], tally)).collect(),
+        by_dir: by_dir.into_iter().collect(),
+        by_arch: by_arch.into_iter().collect(),
+        total: total
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::parser::{FileType, Rules};
+    use super::super::testutil::{file_list, SAMPLE_INDEX};
+
+    #[test]
+    pub fn test_stats() {
+        let files = file_list(format!("{}/Warframe.x64.exe.81E34ABBF3AEFAD7E56D157EDE08E178.lzma,7500000\r\n",
+                                      SAMPLE_INDEX).as_str());
+        let result = stats(&files, &Rules::default());
+        assert_eq!(result.total, Tally { files: 4, bytes: 313612 + 47951 + 6998205 + 7500000 });
+        assert_eq!(result.by_type, vec![
+            (FileType::Exe32Bit, Tally { files: 2, bytes: 47951 + 6998205 }),
+            (FileType::Exe64Bit, Tally { files: 1, bytes: 7500000 }),
+            (FileType::LauncherAsset, Tally { files: 1, bytes: 313612 })
+        ]);
+        assert_eq!(result.by_dir, vec![
+            ("/".to_string(), Tally { files: 2, bytes: 6998205 + 7500000 }),
+            ("/Tools".to_string(), Tally { files: 2, bytes: 313612 + 47951 })
+        ]);
+        assert_eq!(result.by_arch, vec![
+            (Arch::X86, Tally { files: 1, bytes: 47951 }),
+            (Arch::X64, Tally { files: 1, bytes: 7500000 }),
+            (Arch::Any, Tally { files: 2, bytes: 313612 + 6998205 })
+        ]);
+    }
+}
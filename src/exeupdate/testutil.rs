@@ -0,0 +1,70 @@
+//! Helpers shared by the `exeupdate` tests
+
+use super::downloader::md5sum;
+use super::parser::parse_file_list;
+use hex::ToHex;
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// A few lines from a real index, for tests that just need some files to work with
+pub static SAMPLE_INDEX: &'static str = "/Tools/Launcher.exe.F336FD22FDF21024C75FF46FE8F7A06E.lzma,313612\r\n\
+                                         /Tools/Windows/x86/symsrv.dll.64DEA54A4457371DEC27A4CFAE6EFB50.lzma,47951\r\n\
+                                         /Warframe.exe.3BB594902B2E8037901ED9B2419E8FD5.lzma,6998205\r\n";
+
+/// `test_content()` encoded with `lzma.compress(data, format=lzma.FORMAT_ALONE)`
+pub static TEST_LZMA: &'static [u8] = &[
+    0x5D, 0x00, 0x00, 0x80, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x2B, 0x98,
+    0x4A, 0x46, 0x78, 0x90, 0x3F, 0xFB, 0x6A, 0x1A, 0xF9, 0xF1, 0x19, 0x28, 0xAF, 0x62, 0xEA, 0x5F,
+    0x61, 0x7E, 0xA6, 0x6E, 0xAC, 0x05, 0x1B, 0xBF, 0xEE, 0x6B, 0xF9, 0xB7, 0x1A, 0xA3, 0x80, 0x77,
+    0xED, 0x09, 0x60, 0x3B, 0x9D, 0xA5, 0xC6, 0x4C, 0xBE, 0xD3, 0x8A, 0xE5, 0xFD, 0xF7, 0xFF, 0xFE,
+    0xAA, 0x1C, 0x00
+];
+
+/// What `TEST_LZMA` decompresses to
+pub fn test_content() -> Vec<u8> {
+    let mut out = vec![];
+    for _ in 0..8 {
+        out.extend_from_slice(b"Warframe.exe stand-in for wfupdate tests\n");
+    }
+    out
+}
+
+/// Makes a new, empty directory in the system temp dir, named after `name` and a random number
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let mut dir = ::std::env::temp_dir();
+    dir.push(format!("wfupdate-test-{}-{}", name, ::rand::random::<u32>()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes `content` to `path`, making its directory if it has to
+pub fn write(path: &Path, content: &[u8]) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::File::create(path).unwrap().write_all(content).unwrap();
+}
+
+/// Reads all of `path`
+pub fn read(path: &Path) -> Vec<u8> {
+    let mut out = vec![];
+    fs::File::open(path).unwrap().read_to_end(&mut out).unwrap();
+    out
+}
+
+/// Parses `list`, which has to be a valid file list
+pub fn file_list(list: &str) -> Vec<super::File> {
+    parse_file_list(list.to_string()).unwrap()
+}
+
+/// A file at `disk_path` that decompresses to `content`, with a download path to match
+///
+/// The size is `content`'s, as if it didn't compress at all.
+pub fn test_file(disk_path: &str, content: &[u8]) -> super::File {
+    let md5sum = md5sum(content);
+    super::File {
+        download_path: format!("{}.{}.lzma", disk_path, md5sum.to_hex().to_uppercase()),
+        disk_path: disk_path.to_string(),
+        md5sum: md5sum,
+        size: content.len() as u64
+    }
+}
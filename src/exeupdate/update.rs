@@ -1,15 +1,278 @@
-#![allow(missing_docs)]
+//! Warframe File Updater
+//!
+//! Responsible for actually putting downloaded files on disk.
+//!
+//! Files are never written in place. New content goes into a temporary file in the same directory,
+//! gets fsynced, picks up the permissions of the file it's replacing, and is then renamed over the original.
+//! A crash or a full disk mid-write will only ever leave a stray temp file behind, never a half-written `Warframe.exe`.
+//!
+//! `UpdateBatch` extends that to a whole set of files, so a stage 1 update is either applied entirely or not at all.
+
+use rand::Rng;
 use std::io;
 use std::io::prelude::*;
-use std::io::BufWriter;
-use std::fs::File;
-use std::path::PathBuf;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
 
-pub fn update_file(path: PathBuf, newcontent: Vec<u8>) -> io::Result<()> {
-    let f = try!(File::create(path));
-    {
-        let mut writer = BufWriter::new(f);
-        try!(writer.write(newcontent.as_slice()));
+/// Builds a sibling path for `path`, ex `Warframe.exe` becomes `.Warframe.exe.1A2B3C4D.wfupdate-tmp`
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut rng = ::rand::thread_rng();
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => String::from("file")
+    };
+    path.with_file_name(format!(".{}.{:08X}.{}", name, rng.gen::<u32>(), suffix))
+}
+
+/// fsyncs the directory containing `path`, so that renames in it survive a crash.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) => try!(File::open(dir)).sync_all(),
+        None => Ok(())
     }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_: &Path) -> io::Result<()> {
     Ok(())
 }
+
+/// Writes `content` to a new temporary file next to `path`, and returns the temporary file's path.
+///
+/// The temp file is fsynced and, if `path` already exists, given the same permissions as it.
+/// If anything goes wrong the temp file is removed again.
+pub fn write_temp_file(path: &Path, content: &[u8]) -> io::Result<PathBuf> {
+    write_temp_file_with(path, |f| f.write_all(content)).map(|(temp, _)| temp)
+}
+
+/// Like `write_temp_file()`, but the content is written by `write` instead of coming from a slice.
+///
+/// Lets a file be streamed out a piece at a time, without ever holding all of it in memory.
+/// Returns the temporary file's path along with whatever `write` returned.
+pub fn write_temp_file_with<T, F>(path: &Path, write: F) -> io::Result<(PathBuf, T)>
+    where F: FnOnce(&mut File) -> io::Result<T> {
+    let temp = sibling_path(path, "wfupdate-tmp");
+    let result: io::Result<T> = (|| {
+        let mut f = try!(OpenOptions::new().write(true).create_new(true).open(&temp));
+        let out = try!(write(&mut f));
+        try!(f.sync_all());
+        if let Ok(metadata) = fs::metadata(path) {
+            try!(fs::set_permissions(&temp, metadata.permissions()));
+        }
+        Ok(out)
+    })();
+    match result {
+        Ok(out) => Ok((temp, out)),
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+/// Atomically replaces the file at `path` with `newcontent`.
+pub fn update_file(path: PathBuf, newcontent: Vec<u8>) -> io::Result<()> {
+    let temp = try!(write_temp_file(&path, newcontent.as_slice()));
+    if let Err(e) = fs::rename(&temp, &path) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    sync_parent(&path)
+}
+
+/// How `UpdateBatch::commit()` went wrong
+#[derive(Debug)]
+pub enum CommitError {
+    /// A file couldn't be moved into place, so everything was put back the way it was
+    RolledBack(io::Error),
+    /// Every file is in place, but they couldn't all be synced to disk, so a crash soon after could still lose some
+    NotSynced(io::Error)
+}
+
+impl From<CommitError> for io::Error {
+    fn from(err: CommitError) -> io::Error {
+        match err {
+            CommitError::RolledBack(err) | CommitError::NotSynced(err) => err
+        }
+    }
+}
+
+/// A set of file updates that are applied all together, or not at all.
+///
+/// Files are written out to temporary files as they're `stage()`d, and only moved into place by `commit()`.
+/// Dropping a batch without committing it removes the temporary files, leaving the install untouched.
+///
+/// ## Example
+/// ```rust,no_run
+/// let mut batch = UpdateBatch::new();
+/// batch.stage(PathBuf::from("Warframe.exe"), exe).unwrap();
+/// batch.stage(PathBuf::from("Tools/Launcher.exe"), launcher).unwrap();
+/// batch.commit().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct UpdateBatch {
+    /// (temporary file, destination) pairs
+    staged: Vec<(PathBuf, PathBuf)>
+}
+
+impl UpdateBatch {
+    /// Makes a new, empty batch
+    pub fn new() -> Self {
+        UpdateBatch {
+            staged: vec![]
+        }
+    }
+
+    /// Writes `content` out next to `path`, ready to be moved into place on `commit()`.
+    pub fn stage(&mut self, path: PathBuf, content: Vec<u8>) -> io::Result<()> {
+        let temp = try!(write_temp_file(&path, content.as_slice()));
+        self.staged.push((temp, path));
+        Ok(())
+    }
+
+    /// Copies everything from `reader` out next to `path`, ready to be moved into place on `commit()`.
+    pub fn stage_from<R: Read>(&mut self, path: PathBuf, reader: &mut R) -> io::Result<()> {
+        let (temp, _) = try!(write_temp_file_with(&path, |f| io::copy(reader, f)));
+        self.staged.push((temp, path));
+        Ok(())
+    }
+
+    /// Takes over a temp file that's already been written next to `path` (ex by `write_temp_file()`),
+    /// to be moved into place on `commit()`.
+    ///
+    /// From here on the batch is responsible for the temp file, and will delete it if the batch is dropped.
+    pub fn stage_temp(&mut self, path: PathBuf, temp: PathBuf) {
+        self.staged.push((temp, path));
+    }
+
+    /// The number of files staged so far
+    pub fn len(&self) -> usize {
+        self.staged.len()
+    }
+
+    /// Moves every staged file into place.
+    ///
+    /// The originals are moved aside first, and only deleted once every new file is in place.
+    /// If any rename fails, everything already moved is put back the way it was and `CommitError::RolledBack` is returned.
+    /// Once the new files are in place there's no going back, so failing to sync them after that is `CommitError::NotSynced`.
+    pub fn commit(mut self) -> Result<(), CommitError> {
+        let staged = ::std::mem::replace(&mut self.staged, vec![]);
+        // (destination, where the original was moved to)
+        let mut applied: Vec<(PathBuf, Option<PathBuf>)> = vec![];
+        let mut failure = None;
+        for &(ref temp, ref dest) in &staged {
+            let backup = if dest.exists() {
+                let backup = sibling_path(dest, "wfupdate-old");
+                if let Err(e) = fs::rename(dest, &backup) {
+                    failure = Some(e);
+                    break;
+                }
+                Some(backup)
+            } else {
+                None
+            };
+            let result = fs::rename(temp, dest);
+            applied.push((dest.clone(), backup));
+            if let Err(e) = result {
+                failure = Some(e);
+                break;
+            }
+        }
+
+        if let Some(e) = failure {
+            for (dest, backup) in applied.into_iter().rev() {
+                match backup {
+                    Some(backup) => { let _ = fs::rename(backup, &dest); },
+                    None => { let _ = fs::remove_file(&dest); }
+                }
+            }
+            for (temp, _) in staged {
+                let _ = fs::remove_file(temp);
+            }
+            return Err(CommitError::RolledBack(e));
+        }
+
+        for &(_, ref backup) in &applied {
+            if let Some(ref backup) = *backup {
+                let _ = fs::remove_file(backup);
+            }
+        }
+        let mut unsynced = None;
+        for (dest, _) in applied {
+            if let Err(e) = sync_parent(&dest) {
+                unsynced = unsynced.or(Some(e));
+            }
+        }
+        match unsynced {
+            Some(e) => Err(CommitError::NotSynced(e)),
+            None => Ok(())
+        }
+    }
+
+    /// Throws away every staged file without touching the originals.
+    ///
+    /// Dropping the batch does the same thing, this just makes it explicit
+    /// (and works before `std::process::exit`, which doesn't run destructors).
+    pub fn abort(mut self) {
+        self.cleanup();
+    }
+
+    fn cleanup(&mut self) {
+        for (temp, _) in self.staged.drain(..) {
+            let _ = fs::remove_file(temp);
+        }
+    }
+}
+
+impl Drop for UpdateBatch {
+    fn drop(&mut self) {
+        self.cleanup();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testutil::{scratch_dir, read};
+    use std::fs;
+
+    #[test]
+    pub fn test_batch_commit() {
+        let dir = scratch_dir("commit");
+        let existing = dir.join("Warframe.exe");
+        let new = dir.join("Launcher.exe");
+        update_file(existing.clone(), b"old".to_vec()).unwrap();
+
+        let mut batch = UpdateBatch::new();
+        batch.stage(existing.clone(), b"new".to_vec()).unwrap();
+        batch.stage(new.clone(), b"launcher".to_vec()).unwrap();
+        // nothing is touched until commit
+        assert_eq!(read(&existing), b"old");
+        assert!(!new.exists());
+        batch.commit().unwrap();
+
+        assert_eq!(read(&existing), b"new");
+        assert_eq!(read(&new), b"launcher");
+        // no temp files or backups left lying around
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_batch_abort() {
+        let dir = scratch_dir("abort");
+        let existing = dir.join("Warframe.exe");
+        update_file(existing.clone(), b"old".to_vec()).unwrap();
+
+        let mut batch = UpdateBatch::new();
+        batch.stage(existing.clone(), b"new".to_vec()).unwrap();
+        batch.abort();
+
+        assert_eq!(read(&existing), b"old");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+//! Index Entry Sanity Checks
+//!
+//! Paths in the index get joined onto the install directory, so a bad entry (say `/../../.bashrc`)
+//! could otherwise write anywhere the user can. Every entry is checked before anything is written for it,
+//! and entries that fail are reported and skipped.
+
+use std::fmt;
+
+/// The longest index path allowed, in bytes. Windows' `MAX_PATH`, which the game couldn't go past anyway.
+pub const MAX_PATH_LENGTH: usize = 260;
+
+/// Why an index path was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    /// The path doesn't name a file
+    Empty,
+    /// The path has a `..` in it
+    ParentDir,
+    /// The path has a drive letter or some other `:` in it, ex `C:/Windows/System32/evil.dll`
+    DriveLetter,
+    /// The path has a control character (newline, NUL, etc) in it
+    ControlCharacter,
+    /// The path is longer than `MAX_PATH_LENGTH`
+    TooLong(usize)
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PathError::Empty => write!(f, "empty path"),
+            PathError::ParentDir => write!(f, "path goes up a directory with \"..\""),
+            PathError::DriveLetter => write!(f, "path has a drive letter or \":\" in it"),
+            PathError::ControlCharacter => write!(f, "path has a control character in it"),
+            PathError::TooLong(len) => write!(f, "path is {} bytes long, the limit is {}", len, MAX_PATH_LENGTH)
+        }
+    }
+}
+
+/// Checks an index path, and returns it normalized.
+///
+/// Backslashes are treated as `/`, repeated slashes and `.` components are dropped, and the result always starts with `/`.
+///
+/// ## Example
+/// ```rust
+/// assert_eq!(normalize_path("/Tools\\Windows//./x86/symsrv.dll"), Ok("/Tools/Windows/x86/symsrv.dll".to_string()));
+/// assert_eq!(normalize_path("/../../.bashrc"), Err(PathError::ParentDir));
+/// ```
+pub fn normalize_path(path: &str) -> Result<String, PathError> {
+    if path.len() > MAX_PATH_LENGTH {
+        return Err(PathError::TooLong(path.len()));
+    }
+    if path.chars().any(|c| c.is_control()) {
+        return Err(PathError::ControlCharacter);
+    }
+    if path.contains(':') {
+        return Err(PathError::DriveLetter);
+    }
+    let mut out = String::new();
+    for component in path.split(|c| c == '/' || c == '\\') {
+        match component {
+            "" | "." => continue,
+            ".." => return Err(PathError::ParentDir),
+            component => {
+                out.push('/');
+                out.push_str(component);
+            }
+        }
+    }
+    if out.len() == 0 {
+        return Err(PathError::Empty);
+    }
+    Ok(out)
+}
+
+/// Checks every file's `disk_path` and `download_path`.
+///
+/// Returns the files that passed, with their `disk_path`s normalized, and the ones that didn't along with why.
+pub fn filter_files(files: Vec<super::File>) -> (Vec<super::File>, Vec<(super::File, PathError)>) {
+    let mut good = vec![];
+    let mut rejected = vec![];
+    for mut file in files {
+        // download_path is only ever sent to the server or joined onto a mirror directory, so it's checked but left alone.
+        match normalize_path(file.download_path.as_str()).and_then(|_| normalize_path(file.disk_path.as_str())) {
+            Ok(disk_path) => {
+                file.disk_path = disk_path;
+                good.push(file);
+            },
+            Err(err) => rejected.push((file, err))
+        }
+    }
+    (good, rejected)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_normalize_path() {
+        assert_eq!(normalize_path("/Warframe.exe"), Ok("/Warframe.exe".to_string()));
+        assert_eq!(normalize_path("Tools\\Windows//./x86/symsrv.dll"), Ok("/Tools/Windows/x86/symsrv.dll".to_string()));
+        assert_eq!(normalize_path("/../../.bashrc"), Err(PathError::ParentDir));
+        assert_eq!(normalize_path("/Tools\\..\\..\\evil.dll"), Err(PathError::ParentDir));
+        assert_eq!(normalize_path("C:/Windows/System32/evil.dll"), Err(PathError::DriveLetter));
+        assert_eq!(normalize_path("/Warframe.exe\n/etc/passwd"), Err(PathError::ControlCharacter));
+        assert_eq!(normalize_path("/./"), Err(PathError::Empty));
+        let long: String = ::std::iter::once('/').chain(::std::iter::repeat('a').take(MAX_PATH_LENGTH)).collect();
+        assert_eq!(normalize_path(long.as_str()), Err(PathError::TooLong(MAX_PATH_LENGTH + 1)));
+    }
+
+    #[test]
+    pub fn test_filter_files() {
+        let file = |path: &str| super::super::testutil::test_file(path, b"");
+        let (good, rejected) = filter_files(vec![file("/Warframe.exe"), file("/../.bashrc"), file("//Tools/Launcher.exe")]);
+        assert_eq!(good.len(), 2);
+        assert_eq!(good[1].disk_path, "/Tools/Launcher.exe");
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].1, PathError::ParentDir);
+    }
+}
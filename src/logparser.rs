@@ -1,7 +1,17 @@
 //! Utility for parsing a Warframe log lines into a Rust-readable format.
 //!
-//! logparser is only concerned with log lines pertaining to updates, and doesn't parse anything else.
-//! # Log File Format
+//! Every Warframe log line follows the same grammar:
+//!
+//! ```text
+//! <seconds since start> <channel> [<level>]: <message>
+//! 3.245 Net [Warning]: Failed to resolve host
+//! ```
+//!
+//! `parse_entry()` splits any line into a `LogEntry` with those parts, and `parse_line()` goes a step further and
+//! picks out the lines that have to do with updates, giving everything else back as an `Entry` (or `Unknown`
+//! if it doesn't follow the grammar, ex the continuation lines of a multi-line message).
+//!
+//! # Update Lines
 //! The log lines we care about look something like these:
 //!
 //! ```text
@@ -32,12 +42,79 @@
 //! BytesToDownload(4493854909);
 //! UsedShared(25705, "/Lotus/Levels/Proc/Orokin/OrokinMoonDefense");
 //! ```
+//!
+//! Any other line is left as it is, ex `Entry(LogEntry { time: 3245, channel: "Net", level: Warning, .. })`.
 #![warn(missing_docs)]
 extern crate regex;
 
 use std::fmt;
 use self::regex::Regex;
 
+/// How serious a log entry is
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Level {
+    /// `[Info]`
+    Info,
+    /// `[Warning]`
+    Warning,
+    /// `[Error]`
+    Error,
+    /// Any other level, the included string is what was between the brackets.
+    Other(String)
+}
+
+impl<'a> From<&'a str> for Level {
+    fn from(level: &'a str) -> Level {
+        match level {
+            "Info" => Level::Info,
+            "Warning" => Level::Warning,
+            "Error" => Level::Error,
+            other => Level::Other(String::from(other))
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            Level::Info => "Info",
+            Level::Warning => "Warning",
+            Level::Error => "Error",
+            Level::Other(ref level) => &level[..]
+        })
+    }
+}
+
+/// One line of a Warframe log, split into its parts.
+///
+/// The original line is kept too, and displaying the entry gives it back exactly.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LogEntry {
+    /// Milliseconds since the game started (the log has them as seconds, usually with 3 decimal places)
+    pub time: u64,
+    /// Which part of the game logged it, ex `Sys`, `Net`, `Script` or `Game`
+    pub channel: String,
+    /// How serious it is
+    pub level: Level,
+    /// Everything after the `: `
+    pub message: String,
+    /// The line as it was in the log
+    pub raw: String
+}
+
+impl LogEntry {
+    /// Seconds since the game started
+    pub fn seconds(&self) -> f64 {
+        self.time as f64 / 1000.0
+    }
+}
+
+impl fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
 /// A parsed Warframe log line.
 ///
 /// 64-bit unsigned integers are used instead of usize to ensure it doesn't run into problems on 32-bit systems.
@@ -49,7 +126,9 @@ pub enum LogLine {
     BytesToDownload(u64),
     /// The size and name of the file downloaded
     UsedShared(u64, String),
-    /// Unknown line, the included string is the original line.
+    /// Any other log entry
+    Entry(LogEntry),
+    /// A line that isn't a log entry, the included string is the original line.
     Unknown(String)
 }
 
@@ -59,6 +138,7 @@ impl fmt::Display for LogLine {
             LogLine::HashMismatch(_) => "HashMismatch",
             LogLine::BytesToDownload(_) => "BytesToDownload",
             LogLine::UsedShared(_, _) => "UsedShared",
+            LogLine::Entry(_) => "Entry",
             LogLine::Unknown(_) => "Unknown"
         })
     }
@@ -88,6 +168,42 @@ fn parse_bytes(input: &str) -> u64 {
 }
 
 
+/// Turns the log's `seconds.millis` timestamp into milliseconds
+fn parse_time(seconds: &str, millis: &str) -> Option<u64> {
+    // Pad or cut the fraction to 3 digits, so "1.5" is 1500ms
+    let millis: String = millis.chars().chain("000".chars()).take(3).collect();
+    match (seconds.parse::<u64>(), millis.parse::<u64>()) {
+        (Ok(seconds), Ok(millis)) => seconds.checked_mul(1000).and_then(|ms| ms.checked_add(millis)),
+        _ => None
+    }
+}
+
+/// Splits a line into its timestamp, channel, level and message.
+///
+/// Returns `None` for lines that don't look like `<seconds> <channel> [<level>]: <message>`.
+pub fn parse_entry(line: &str) -> Option<LogEntry> {
+    lazy_static! {
+        static ref RE_ENTRY: Regex =
+            Regex::new(r"^\s*(?P<seconds>[0-9]+)\.(?P<millis>[0-9]+)\s(?P<channel>[^\s\[]+)\s\[(?P<level>[^\]]+)\]: ?(?P<message>.*)$").unwrap();
+    }
+
+    let captures = match RE_ENTRY.captures(line.trim_right_matches(|c| c == '\r' || c == '\n')) {
+        Some(captures) => captures,
+        None => return None
+    };
+    let time = match parse_time(captures.name("seconds").unwrap(), captures.name("millis").unwrap()) {
+        Some(time) => time,
+        None => return None
+    };
+    Some(LogEntry {
+        time: time,
+        channel: String::from(captures.name("channel").unwrap()),
+        level: Level::from(captures.name("level").unwrap()),
+        message: String::from(captures.name("message").unwrap()),
+        raw: String::from(line)
+    })
+}
+
 /// Parses a line and returns a LogLine representing the usable value of the line.
 ///
 /// See the documentation for the crate for more info.
@@ -96,25 +212,36 @@ fn parse_bytes(input: &str) -> u64 {
 /// * `line` The line to parse
 ///
 pub fn parse_line(line: &str) -> LogLine {
+    match parse_entry(line) {
+        Some(entry) => parse_update_entry(entry),
+        None => LogLine::Unknown(String::from(line))
+    }
+}
+
+/// Picks out the update lines from an entry, giving back anything else as `LogLine::Entry`
+fn parse_update_entry(entry: LogEntry) -> LogLine {
     lazy_static! {
         static ref RE_USEDSHARED: Regex =
-            Regex::new(r"[0-9\.]+\sSys\s\[Info\]: Used shared (?P<file>[^\s]+) \((?P<size>[0-9,]+)[^)]+\)").unwrap();
+            Regex::new(r"^Used shared (?P<file>[^\s]+) \((?P<size>[0-9,]+)[^)]+\)").unwrap();
         static ref RE_BYTESTODOWNLOAD: Regex =
-            Regex::new(r"[0-9\.]+\sSys\s\[Info\]: (?P<size>[0-9,]+) bytes to download").unwrap();
+            Regex::new(r"^(?P<size>[0-9,]+) bytes to download").unwrap();
         static ref RE_HASHMISMATCH: Regex =
-            Regex::new(r"[0-9\.]+\sSys\s\[Info\]: (?P<file>[^\s]+) is out of date \(hash mismatch\)").unwrap();
+            Regex::new(r"^(?P<file>[^\s]+) is out of date \(hash mismatch\)").unwrap();
     }
 
-    if let Some(captures) = RE_USEDSHARED.captures(line) {
+    if entry.channel != "Sys" || entry.level != Level::Info {
+        return LogLine::Entry(entry);
+    }
+    if let Some(captures) = RE_USEDSHARED.captures(&entry.message) {
         return LogLine::UsedShared(parse_bytes(captures.name("size").unwrap()), String::from(captures.name("file").unwrap()));
     }
-    if let Some(captures) = RE_HASHMISMATCH.captures(line) {
+    if let Some(captures) = RE_HASHMISMATCH.captures(&entry.message) {
         return LogLine::HashMismatch(String::from(captures.name("file").unwrap()));
     }
-    if let Some(captures) = RE_BYTESTODOWNLOAD.captures(line) {
+    if let Some(captures) = RE_BYTESTODOWNLOAD.captures(&entry.message) {
         return LogLine::BytesToDownload(parse_bytes(captures.name("size").unwrap()));
     }
-    LogLine::Unknown(String::from(""))
+    LogLine::Entry(entry)
 }
 
 
@@ -144,6 +271,7 @@ pub fn parse_lines(input: &str) -> Vec<LogLine> {
 #[cfg(test)]
 mod tests {
 
+    use super::{Level, LogEntry};
     use super::LogLine::*;
 
     #[test]
@@ -177,4 +305,48 @@ mod tests {
         let result = super::parse_line("109.880 Sys [Info]: Used shared /Lotus/Objects/Natural/Skybox/TennoHanger/GasPlaneOptA.fbx (3,607B Copy: 1.20s Write: 0s Latency: 95.6s)");
         assert_eq!(result, UsedShared(3607, String::from("/Lotus/Objects/Natural/Skybox/TennoHanger/GasPlaneOptA.fbx")));
     }
+
+    #[test]
+    pub fn test_parse_entry() {
+        let line = "3.245 Net [Warning]: Failed to resolve host: [content.warframe.com]";
+        let entry = super::parse_entry(line).unwrap();
+        assert_eq!(entry, LogEntry {
+            time: 3245,
+            channel: String::from("Net"),
+            level: Level::Warning,
+            message: String::from("Failed to resolve host: [content.warframe.com]"),
+            raw: String::from(line)
+        });
+        assert_eq!(entry.to_string(), line);
+        assert_eq!(super::parse_line(line), Entry(entry));
+
+        let entry = super::parse_entry("1012.5 Script [Diag]: ").unwrap();
+        assert_eq!(entry.time, 1012500);
+        assert_eq!(entry.level, Level::Other(String::from("Diag")));
+        assert_eq!(entry.message, "");
+    }
+
+    #[test]
+    pub fn test_entry_round_trip() {
+        for line in &["  1.5 Sys [Info]:no space\r",
+                      "12.34567 Game [Error]: more digits than usual",
+                      "0.000 Net [Warning]:   extra spaces  "] {
+            let entry = super::parse_entry(line).unwrap();
+            assert_eq!(entry.to_string(), *line);
+        }
+        assert_eq!(super::parse_entry("12.34567 Game [Error]: x").unwrap().time, 12345);
+    }
+
+    #[test]
+    pub fn test_only_sys_info_is_typed() {
+        let line = "14.182 Game [Error]: 4,493,854,909 bytes to download";
+        assert_eq!(super::parse_line(line), Entry(super::parse_entry(line).unwrap()));
+    }
+
+    #[test]
+    pub fn test_unknown_keeps_line() {
+        assert_eq!(super::parse_line("    at Lotus/Scripts/Foo.lua:12"), Unknown(String::from("    at Lotus/Scripts/Foo.lua:12")));
+        assert_eq!(super::parse_line(""), Unknown(String::from("")));
+        assert!(super::parse_entry("Sys [Info]: no timestamp").is_none());
+    }
 }
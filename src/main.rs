@@ -11,6 +11,10 @@ extern crate xdg;
 extern crate ini;
 extern crate time;
 extern crate encoding;
+extern crate num_cpus;
+extern crate libc;
+#[cfg(feature = "https")]
+extern crate openssl;
 pub mod logparser;
 pub mod paths;
 pub mod wine;
@@ -62,7 +66,7 @@ fn display_parsed(parsed: &Vec<LogLine>) {
                 downloaded_bytes += size;
                 downloaded_files += 1;
             },
-            &LogLine::Unknown(_) => {}
+            &LogLine::Entry(_) | &LogLine::Unknown(_) => {}
         }
     }
 
@@ -106,6 +110,10 @@ fn main() {
                        .subcommand(cli::wine::subcommand())
                        .subcommand(cli::update::subcommand())
                        .subcommand(cli::repair::subcommand())
+                       .subcommand(cli::mirror::subcommand())
+                       .subcommand(cli::index::subcommand())
+                       .subcommand(cli::clean::subcommand())
+                       .subcommand(cli::cache::subcommand())
                        .get_matches();
 
     if let Some(matches) = matches.subcommand_matches("parse") {
@@ -132,5 +140,13 @@ fn main() {
         cli::config::run(matches);
     } else if let Some(matches) = matches.subcommand_matches("repair") {
         cli::repair::run(matches);
+    } else if let Some(matches) = matches.subcommand_matches("mirror") {
+        cli::mirror::run(matches);
+    } else if let Some(matches) = matches.subcommand_matches("index") {
+        cli::index::run(matches);
+    } else if let Some(matches) = matches.subcommand_matches("clean") {
+        cli::clean::run(matches);
+    } else if let Some(matches) = matches.subcommand_matches("cache") {
+        cli::cache::run(matches);
     }
 }
@@ -1,4 +1,5 @@
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 
 #[cfg(unix)]
 pub mod wine;
@@ -44,8 +45,39 @@ pub fn game_install_dir() -> Option<PathBuf> {
 }
 
 
+/// How many bytes are free (to this user) on the filesystem holding `path`
+#[inline(always)]
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    self::plat::free_space(path)
+}
+
+/// An ID for the filesystem holding `path`, the same for every path on that filesystem
+#[inline(always)]
+pub fn filesystem_id(path: &Path) -> io::Result<u64> {
+    self::plat::filesystem_id(path)
+}
+
+/// Sets `path`'s modification time to now
+#[inline(always)]
+pub fn touch(path: &Path) -> io::Result<()> {
+    self::plat::touch(path)
+}
+
+/// Whether an index path belongs in the Launcher's directory rather than the game's.
+pub fn is_launcher_path(input: &str) -> bool {
+    input.starts_with("/Tools/CEF3_1") || input.starts_with("/Tools/Launcher.exe")
+}
+
+/// Works out where a file from the index goes on disk, see `exeupdate::checker::check_file()` for examples.
+///
+/// Returns `None` if the install can't be found, or if the path could end up outside of it
+/// (see `exeupdate::validate::normalize_path()`).
 pub fn realize_path(input: String) -> Option<PathBuf> {
-    if input.starts_with("/Tools/CEF3_1") || input.starts_with("/Tools/Launcher.exe") {
+    let input = match ::exeupdate::validate::normalize_path(input.as_str()) {
+        Ok(path) => path,
+        Err(_) => return None
+    };
+    if is_launcher_path(input.as_str()) {
         // Launcher file
         let mut out = match launcher_dir() {
             Some(path) => path,
@@ -2,8 +2,16 @@
 //!
 
 use users::get_current_username;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::env;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::fs;
+use libc;
 use super::super::config;
 
 /// Figures out the user's wineprefix
@@ -80,3 +88,32 @@ pub fn launcher_dir() -> Option<PathBuf> {
         return None;
     }
 }
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(p) => Ok(p),
+        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "path has a NUL in it"))
+    }
+}
+
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    let cpath = try!(c_path(path));
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // f_bavail leaves out the blocks reserved for root
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+pub fn filesystem_id(path: &Path) -> io::Result<u64> {
+    fs::metadata(path).map(|metadata| metadata.dev())
+}
+
+pub fn touch(path: &Path) -> io::Result<()> {
+    let cpath = try!(c_path(path));
+    if unsafe { libc::utime(cpath.as_ptr(), ptr::null()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}